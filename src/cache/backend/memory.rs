@@ -0,0 +1,433 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{CacheBackend, CacheBackendInvalidationHandler, CacheBackendMeta, CacheBackendResult};
+use crate::cache::lru::CacheLru;
+use crate::cache::route::ROUTE_PREFIX;
+use crate::cache::store::{
+    CachePurgeVariant, CacheStoreDeadlines, CacheStoreError, CacheStoreMeta,
+};
+use crate::APP_CONF;
+
+pub struct CacheBackendMemoryBuilder;
+
+pub struct CacheBackendMemory {
+    state: Mutex<CacheBackendMemoryState>,
+}
+
+struct CacheBackendMemoryState {
+    entries: CacheLru<CacheBackendMemoryEntry>,
+    tags: HashMap<String, HashSet<String>>,
+}
+
+struct CacheBackendMemoryEntry {
+    mask: String,
+    fingerprint: String,
+    tags: Vec<String>,
+    body: Vec<u8>,
+    fresh_until: u64,
+    stale_until: u64,
+    vary: Vec<String>,
+    expires_at: Instant,
+}
+
+impl CacheBackendMemoryBuilder {
+    pub fn create() -> CacheBackendMemory {
+        info!(
+            "binding to memory store backend with a budget of {} bytes",
+            APP_CONF.memory.max_size
+        );
+
+        CacheBackendMemory::new(APP_CONF.memory.max_size)
+    }
+}
+
+impl CacheBackendMemory {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            state: Mutex::new(CacheBackendMemoryState {
+                entries: CacheLru::new(max_size),
+                tags: HashMap::new(),
+            }),
+        }
+    }
+}
+
+impl CacheBackendMemoryState {
+    fn acquire_live(&mut self, key: &str) -> Option<&CacheBackendMemoryEntry> {
+        let is_live = match self.entries.peek(key) {
+            Some(entry) => {
+                // Expired entries are dropped upon access, as Redis would do
+                Instant::now() < entry.expires_at
+                    && entry.tags.iter().all(|tag| self.tags.contains_key(tag))
+            }
+            None => return None,
+        };
+
+        if is_live {
+            self.entries.get(key)
+        } else {
+            self.unlink(key);
+
+            None
+        }
+    }
+
+    fn store(&mut self, key: &str, entry: CacheBackendMemoryEntry) -> CacheBackendResult<()> {
+        // Account for all owned data, as this is what the memory budget is made of
+        let entry_size = key.len()
+            + entry.mask.len()
+            + entry.fingerprint.len()
+            + entry.body.len()
+            + entry.tags.iter().map(String::len).sum::<usize>()
+            + entry.vary.iter().map(String::len).sum::<usize>();
+
+        let (entry_mask, entry_tags) = (entry.mask.to_owned(), entry.tags.to_owned());
+
+        // Drop any previous value first, as its tags may differ from the new value tags
+        self.unlink(key);
+
+        let evicted = self.entries.insert(key.to_string(), entry, entry_size);
+
+        for (evicted_key, evicted_entry) in &evicted {
+            if evicted_key == key {
+                return Err(CacheStoreError::TooLarge);
+            }
+
+            debug!("evicted key from memory store: {}", evicted_key);
+
+            Self::unindex(&mut self.tags, evicted_entry);
+        }
+
+        for entry_tag in entry_tags {
+            self.tags
+                .entry(entry_tag)
+                .or_default()
+                .insert(entry_mask.to_owned());
+        }
+
+        debug!(
+            "memory store now uses {} bytes after write",
+            self.entries.size()
+        );
+
+        Ok(())
+    }
+
+    fn unlink(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            Self::unindex(&mut self.tags, &entry);
+        }
+    }
+
+    fn unindex(tags: &mut HashMap<String, HashSet<String>>, entry: &CacheBackendMemoryEntry) {
+        for tag in &entry.tags {
+            if let Some(tag_masks) = tags.get_mut(tag) {
+                tag_masks.remove(&entry.mask);
+
+                if tag_masks.is_empty() {
+                    tags.remove(tag);
+                }
+            }
+        }
+    }
+}
+
+impl CacheBackend for CacheBackendMemory {
+    fn get_meta(&self, _shard: u8, key: &str) -> CacheBackendResult<Option<CacheBackendMeta>> {
+        let mut state = self.state.lock().or(Err(CacheStoreError::Failed))?;
+
+        Ok(state.acquire_live(key).map(|entry| CacheBackendMeta {
+            fingerprint: entry.fingerprint.to_owned(),
+            tags: entry.tags.to_owned(),
+            fresh_until: entry.fresh_until,
+            stale_until: entry.stale_until,
+            vary: entry.vary.to_owned(),
+        }))
+    }
+
+    fn get_body(&self, key: &str) -> CacheBackendResult<Option<Vec<u8>>> {
+        let mut state = self.state.lock().or(Err(CacheStoreError::Failed))?;
+
+        Ok(state.acquire_live(key).map(|entry| entry.body.to_owned()))
+    }
+
+    fn set(
+        &self,
+        key: &str,
+        key_mask: &str,
+        value: &[u8],
+        fingerprint: &str,
+        deadlines: &CacheStoreDeadlines,
+        key_tags: &[(String, String)],
+    ) -> CacheBackendResult<()> {
+        let mut state = self.state.lock().or(Err(CacheStoreError::Failed))?;
+
+        let entry = CacheBackendMemoryEntry {
+            mask: key_mask.to_string(),
+            fingerprint: fingerprint.to_string(),
+            tags: key_tags
+                .iter()
+                .map(|key_tag| key_tag.0.to_owned())
+                .collect(),
+            body: value.to_vec(),
+            fresh_until: deadlines.fresh_until,
+            stale_until: deadlines.stale_until,
+            vary: Vec::new(),
+            expires_at: Instant::now() + Duration::from_secs(deadlines.expire_after as u64),
+        };
+
+        state.store(key, entry)
+    }
+
+    fn set_vary(&self, key: &str, vary: &[String], ttl: usize) -> CacheBackendResult<()> {
+        let mut state = self.state.lock().or(Err(CacheStoreError::Failed))?;
+
+        let entry = CacheBackendMemoryEntry {
+            mask: String::new(),
+            fingerprint: String::new(),
+            tags: Vec::new(),
+            body: Vec::new(),
+            fresh_until: 0,
+            stale_until: 0,
+            vary: vary.to_vec(),
+            expires_at: Instant::now() + Duration::from_secs(ttl as u64),
+        };
+
+        state.store(key, entry)
+    }
+
+    fn acquire_lock(
+        &self,
+        _key: &str,
+        _token: &str,
+        _ttl_seconds: u64,
+    ) -> CacheBackendResult<bool> {
+        // Values are local to this Bloom instance, thus there is no other instance to lock out
+        Ok(true)
+    }
+
+    fn release_lock(&self, _key: &str, _token: &str) -> CacheBackendResult<()> {
+        Ok(())
+    }
+
+    fn has_lock(&self, _key: &str) -> CacheBackendResult<bool> {
+        Ok(false)
+    }
+
+    fn purge_tag(
+        &self,
+        variant: &CachePurgeVariant,
+        shard: u8,
+        key_tag: &str,
+    ) -> CacheBackendResult<()> {
+        let mut state = self.state.lock().or(Err(CacheStoreError::Failed))?;
+
+        // Same stale marking as the Redis stale mark script: cache keys listed in the tag set \
+        //   are kept along with the tag set itself, though they are not fresh anymore.
+        if variant.is_soft() {
+            let now = CacheStoreMeta::now();
+
+            let tag_masks = state.tags.get(key_tag).cloned().unwrap_or_default();
+
+            for tag_mask in tag_masks {
+                let key = format!("{ROUTE_PREFIX}:{shard}:c:{tag_mask}");
+
+                if let Some(entry) = state.entries.peek_mut(&key) {
+                    if let Some((fresh_until, stale_until)) =
+                        CacheStoreMeta::stale_at(entry.fresh_until, entry.stale_until, now)
+                    {
+                        entry.fresh_until = fresh_until;
+                        entry.stale_until = stale_until;
+                    }
+                }
+            }
+
+            return Ok(());
+        }
+
+        // Same keyspace cleanup as the Redis purge script: unlink all cache keys listed in \
+        //   the tag set, and then the tag set itself.
+        if let Some(tag_masks) = state.tags.remove(key_tag) {
+            for tag_mask in tag_masks {
+                state.unlink(&format!("{ROUTE_PREFIX}:{shard}:c:{tag_mask}"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn publish_invalidation(&self, _key_tag: &str) -> CacheBackendResult<()> {
+        // Memory store is local to this Bloom instance, there are no other instances to notify
+        Ok(())
+    }
+
+    fn subscribe_invalidations(&self, _handler: CacheBackendInvalidationHandler) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_value(memory: &CacheBackendMemory, route_hash: &str, bucket_hash: &str) {
+        let key_mask = format!("dc56d17a:{route_hash}");
+
+        assert!(memory
+            .set(
+                &format!("bloom:0:c:{key_mask}"),
+                &key_mask,
+                b"HTTP/1.1 200 OK\n\n{}",
+                "f773d6f1",
+                &CacheStoreDeadlines {
+                    fresh_until: 0,
+                    stale_until: 0,
+                    expire_after: 600,
+                },
+                &[
+                    (
+                        format!("bloom:0:b:{bucket_hash}"),
+                        format!("b:{bucket_hash}")
+                    ),
+                    ("bloom:0:a:dc56d17a".to_string(), "a:dc56d17a".to_string()),
+                ],
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn it_stores_and_reads_values() {
+        let memory = CacheBackendMemory::new(10_000);
+
+        store_value(&memory, "7cf7a048", "2eb6c00c");
+
+        assert_eq!(
+            memory
+                .get_meta(0, "bloom:0:c:dc56d17a:7cf7a048")
+                .unwrap()
+                .map(|meta| meta.fingerprint),
+            Some("f773d6f1".to_string())
+        );
+        assert_eq!(
+            memory.get_body("bloom:0:c:dc56d17a:7cf7a048").unwrap(),
+            Some(b"HTTP/1.1 200 OK\n\n{}".to_vec())
+        );
+        assert!(memory
+            .get_meta(0, "bloom:0:c:dc56d17a:0")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn it_purges_bucket_and_auth_tags() {
+        let memory = CacheBackendMemory::new(10_000);
+
+        store_value(&memory, "7cf7a048", "2eb6c00c");
+        store_value(&memory, "b09a457c", "b44c6f8e");
+
+        assert!(memory
+            .purge_tag(&CachePurgeVariant::Bucket, 0, "bloom:0:b:2eb6c00c")
+            .is_ok());
+
+        assert!(memory
+            .get_meta(0, "bloom:0:c:dc56d17a:7cf7a048")
+            .unwrap()
+            .is_none());
+        assert!(memory
+            .get_meta(0, "bloom:0:c:dc56d17a:b09a457c")
+            .unwrap()
+            .is_some());
+
+        assert!(memory
+            .purge_tag(&CachePurgeVariant::Auth, 0, "bloom:0:a:dc56d17a")
+            .is_ok());
+
+        assert!(memory
+            .get_meta(0, "bloom:0:c:dc56d17a:b09a457c")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn it_soft_purges_bucket_tags() {
+        let memory = CacheBackendMemory::new(10_000);
+
+        store_value(&memory, "7cf7a048", "2eb6c00c");
+        store_value(&memory, "b09a457c", "b44c6f8e");
+
+        assert!(memory
+            .purge_tag(&CachePurgeVariant::BucketSoft, 0, "bloom:0:b:2eb6c00c")
+            .is_ok());
+
+        let meta = memory
+            .get_meta(0, "bloom:0:c:dc56d17a:7cf7a048")
+            .unwrap()
+            .unwrap();
+
+        assert!(meta.fresh_until > 0);
+        assert_eq!(meta.fresh_until, meta.stale_until);
+        assert_eq!(
+            memory
+                .get_meta(0, "bloom:0:c:dc56d17a:b09a457c")
+                .unwrap()
+                .map(|meta| meta.fresh_until),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn it_evicts_values_over_budget() {
+        let memory = CacheBackendMemory::new(200);
+
+        store_value(&memory, "7cf7a048", "2eb6c00c");
+        store_value(&memory, "b09a457c", "b44c6f8e");
+
+        assert!(memory
+            .get_meta(0, "bloom:0:c:dc56d17a:7cf7a048")
+            .unwrap()
+            .is_none());
+        assert!(memory
+            .get_meta(0, "bloom:0:c:dc56d17a:b09a457c")
+            .unwrap()
+            .is_some());
+
+        // Evicted value should not linger in its bucket tag set anymore
+        assert!(!memory
+            .state
+            .lock()
+            .unwrap()
+            .tags
+            .contains_key("bloom:0:b:2eb6c00c"));
+    }
+
+    #[test]
+    fn it_expires_values() {
+        let memory = CacheBackendMemory::new(10_000);
+
+        assert!(memory
+            .set(
+                "bloom:0:c:dc56d17a:7cf7a048",
+                "dc56d17a:7cf7a048",
+                b"",
+                "f773d6f1",
+                &CacheStoreDeadlines {
+                    fresh_until: 0,
+                    stale_until: 0,
+                    expire_after: 0,
+                },
+                &[]
+            )
+            .is_ok());
+
+        assert!(memory
+            .get_meta(0, "bloom:0:c:dc56d17a:7cf7a048")
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,63 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use super::store::{CachePurgeVariant, CacheStoreDeadlines, CacheStoreError};
+
+pub mod memory;
+pub mod redis;
+pub mod redis_cluster;
+pub mod redis_sentinel;
+
+pub struct CacheBackendMeta {
+    pub fingerprint: String,
+    pub tags: Vec<String>,
+    pub fresh_until: u64,
+    pub stale_until: u64,
+    pub vary: Vec<String>,
+}
+
+pub enum CacheBackendInvalidation {
+    All,
+    Tag(String),
+}
+
+pub type CacheBackendResult<T> = Result<T, CacheStoreError>;
+pub type CacheBackendInvalidationHandler = Box<dyn Fn(CacheBackendInvalidation) + Send>;
+
+pub trait CacheBackend: Send + Sync {
+    fn get_meta(&self, shard: u8, key: &str) -> CacheBackendResult<Option<CacheBackendMeta>>;
+
+    fn get_body(&self, key: &str) -> CacheBackendResult<Option<Vec<u8>>>;
+
+    fn set(
+        &self,
+        key: &str,
+        key_mask: &str,
+        value: &[u8],
+        fingerprint: &str,
+        deadlines: &CacheStoreDeadlines,
+        key_tags: &[(String, String)],
+    ) -> CacheBackendResult<()>;
+
+    fn set_vary(&self, key: &str, vary: &[String], ttl: usize) -> CacheBackendResult<()>;
+
+    fn acquire_lock(&self, key: &str, token: &str, ttl_seconds: u64) -> CacheBackendResult<bool>;
+
+    fn release_lock(&self, key: &str, token: &str) -> CacheBackendResult<()>;
+
+    fn has_lock(&self, key: &str) -> CacheBackendResult<bool>;
+
+    fn purge_tag(
+        &self,
+        variant: &CachePurgeVariant,
+        shard: u8,
+        key_tag: &str,
+    ) -> CacheBackendResult<()>;
+
+    fn publish_invalidation(&self, key_tag: &str) -> CacheBackendResult<()>;
+
+    fn subscribe_invalidations(&self, handler: CacheBackendInvalidationHandler);
+}
@@ -0,0 +1,799 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use r2d2::{ManageConnection, Pool};
+use redis::{
+    self, ClientTlsConfig, Cmd, Commands, ConnectionAddr, ConnectionInfo, ConnectionLike,
+    ErrorKind, RedisConnectionInfo, RedisError, RedisResult, Script, TlsCertificates, Value,
+};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use super::redis_sentinel::CacheBackendRedisSentinel;
+use super::{
+    CacheBackend, CacheBackendInvalidation, CacheBackendInvalidationHandler, CacheBackendMeta,
+    CacheBackendResult,
+};
+use crate::cache::route::ROUTE_PREFIX;
+use crate::cache::store::{
+    CachePurgeVariant, CacheStoreDeadlines, CacheStoreError, CacheStoreMeta,
+};
+use crate::config::config::ConfigRedisMode;
+use crate::{APP_CONF, THREAD_NAME_CACHE_INVALIDATION};
+
+pub static KEY_BODY: &str = "b";
+pub static KEY_FINGERPRINT: &str = "f";
+pub static KEY_TAGS: &str = "t";
+pub static KEY_FRESH_UNTIL: &str = "e";
+pub static KEY_STALE_UNTIL: &str = "s";
+pub static KEY_VARY: &str = "v";
+pub static KEY_TAGS_SEPARATOR: &str = ",";
+pub static KEY_VARY_SEPARATOR: &str = ",";
+
+// Only release a lock if it is still owned, as it may have expired and been acquired by \
+//   another Bloom instance in the meantime
+pub static LOCK_RELEASE_SCRIPT: &str = r#"
+  if redis.call('GET', KEYS[1]) == ARGV[1] then
+      redis.call('DEL', KEYS[1])
+  end
+"#;
+
+lazy_static! {
+    pub static ref INVALIDATION_CHANNEL: String = format!("{ROUTE_PREFIX}:invalidate");
+
+    // Mark a cache key as stale from now on (ARGV[1]), following 'CacheStoreMeta::stale_at()'
+    // Notice: this script is invoked from its hash (EVALSHA) by soft purges on all Redis \
+    //   backends, thus it must be loaded first (see 'CacheBackendRedis::load_stale_mark()').
+    pub static ref STALE_MARK_SCRIPT: Script = Script::new(
+        r#"
+          local fresh_until = tonumber(redis.call('HGET', KEYS[1], 'e'))
+
+          if fresh_until == 0 then
+              redis.call('HSET', KEYS[1], 'e', ARGV[1], 's', ARGV[1])
+          elseif fresh_until and tonumber(ARGV[1]) < fresh_until then
+              local stale_until = tonumber(redis.call('HGET', KEYS[1], 's')) or 0
+
+              redis.call(
+                  'HSET', KEYS[1], 'e', ARGV[1],
+                  's', tonumber(ARGV[1]) + math.max(stale_until - fresh_until, 0)
+              )
+          end
+        "#
+    );
+}
+
+pub struct CacheBackendRedisBuilder;
+
+pub struct CacheBackendRedis {
+    target: Arc<RwLock<CacheBackendRedisTarget>>,
+    replicas: Vec<Pool<redis::Client>>,
+    replicas_health: CacheBackendRedisReplicasHealth,
+}
+
+pub struct CacheBackendRedisReplicasHealth {
+    cursor: AtomicUsize,
+    down_until: Vec<AtomicU64>,
+}
+
+pub struct CacheBackendRedisTarget {
+    pub addr: ConnectionAddr,
+    client: redis::Client,
+    pool: Pool<redis::Client>,
+}
+
+const MAX_I64_TTL: usize = i64::MAX as usize;
+
+pub const PURGE_BATCH_SIZE: usize = 1000;
+
+// Replicas that failed to serve a read are skipped for this many seconds
+const REPLICA_DOWN_BACKOFF_SECONDS: u64 = 5;
+
+pub fn safe_usize_to_i64(value: usize) -> i64 {
+    if value > MAX_I64_TTL {
+        i64::MAX
+    } else {
+        value as i64
+    }
+}
+
+impl CacheBackendRedisBuilder {
+    pub fn create() -> CacheBackendRedis {
+        // Resolve the current primary from sentinels, or use the configured host
+        let sentinel = match APP_CONF.redis.mode {
+            ConfigRedisMode::Sentinel => Some(CacheBackendRedisSentinel::new()),
+            _ => None,
+        };
+
+        let addr = match sentinel {
+            Some(ref sentinel) => sentinel
+                .discover_primary()
+                .expect("could not discover redis primary from sentinels"),
+            None => match APP_CONF.redis.socket_path {
+                Some(ref socket_path) => ConnectionAddr::Unix(PathBuf::from(socket_path)),
+                None => Self::gen_node_addr(APP_CONF.redis.host.to_owned(), APP_CONF.redis.port),
+            },
+        };
+
+        info!("binding to redis store backend at {}", addr);
+
+        let target = match Self::connect(addr) {
+            Ok(target) => Arc::new(RwLock::new(target)),
+            Err(e) => panic!("{e}"),
+        };
+
+        info!("bound to redis store backend");
+
+        // Follow primary failovers as announced by sentinels
+        if let Some(sentinel) = sentinel {
+            sentinel.watch(target.clone());
+        }
+
+        let replicas = Self::connect_replicas();
+        let replicas_health = CacheBackendRedisReplicasHealth::new(replicas.len());
+
+        CacheBackendRedis {
+            target,
+            replicas,
+            replicas_health,
+        }
+    }
+
+    fn connect_replicas() -> Vec<Pool<redis::Client>> {
+        APP_CONF
+            .redis
+            .replica_nodes
+            .iter()
+            .filter_map(|node| {
+                info!("binding to redis read replica at {}", node);
+
+                // Do not wait for replicas to be up, as reads fall back to the primary anyway
+                match Self::open_node_client(node, true) {
+                    Ok(manager) => Some(Self::pool_builder().build_unchecked(manager)),
+                    Err(err) => {
+                        error!("could not create redis replica connection manager: {}", err);
+
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    pub fn connect(addr: ConnectionAddr) -> Result<CacheBackendRedisTarget, String> {
+        debug!("will connect to redis at: {}", addr);
+
+        match Self::open_client(Self::gen_connection_info(addr.clone(), true)) {
+            Ok(manager) => match Self::pool_builder().build(manager.clone()) {
+                Ok(pool) => Ok(CacheBackendRedisTarget {
+                    addr,
+                    client: manager,
+                    pool,
+                }),
+                Err(e) => Err(format!("could not spawn redis pool: {e}")),
+            },
+            Err(e) => Err(format!("could not create redis connection manager: {e}")),
+        }
+    }
+
+    pub fn open_client(connection_info: ConnectionInfo) -> RedisResult<redis::Client> {
+        match connection_info.addr {
+            ConnectionAddr::TcpTls { .. } => {
+                redis::Client::build_with_tls(connection_info, Self::read_tls_certificates()?)
+            }
+            _ => redis::Client::open(connection_info),
+        }
+    }
+
+    pub fn open_node_client(node: &str, with_auth: bool) -> RedisResult<redis::Client> {
+        Self::open_client(Self::gen_node_connection_info(node, with_auth)?)
+    }
+
+    pub fn gen_node_connection_info(node: &str, with_auth: bool) -> RedisResult<ConnectionInfo> {
+        let (host, port) = Self::parse_node(node).ok_or_else(|| {
+            RedisError::from((ErrorKind::InvalidClientConfig, "invalid redis node address"))
+        })?;
+
+        Ok(Self::gen_connection_info(
+            Self::gen_node_addr(host, port),
+            with_auth,
+        ))
+    }
+
+    pub fn parse_node(node: &str) -> Option<(String, u16)> {
+        // Nodes are formatted as 'host:port', where IPv6 hosts are enclosed in brackets
+        let (host, port) = node.rsplit_once(':')?;
+
+        Some((
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            port.parse().ok()?,
+        ))
+    }
+
+    pub fn gen_node_addr(host: String, port: u16) -> ConnectionAddr {
+        // Notice: TLS parameters (CA bundle and client certificate) are attached upon building \
+        //   the client (see 'open_client'), for all of primary, replica, sentinel and cluster \
+        //   nodes. The redis client always sends the node host as SNI server name and offers no \
+        //   way to override it, which is why there is no 'tls_server_name' option.
+        if APP_CONF.redis.tls {
+            ConnectionAddr::TcpTls {
+                host,
+                port,
+                insecure: false,
+                tls_params: None,
+            }
+        } else {
+            ConnectionAddr::Tcp(host, port)
+        }
+    }
+
+    pub fn gen_connection_info(addr: ConnectionAddr, with_auth: bool) -> ConnectionInfo {
+        // Sentinels do not share credentials with Redis nodes, thus authentication is optional
+        let (username, password) = if with_auth {
+            (
+                APP_CONF.redis.username.to_owned(),
+                APP_CONF.redis.password.to_owned(),
+            )
+        } else {
+            (None, None)
+        };
+
+        ConnectionInfo {
+            addr,
+            redis: RedisConnectionInfo {
+                db: APP_CONF.redis.database as i64,
+                username,
+                password,
+                ..RedisConnectionInfo::default()
+            },
+        }
+    }
+
+    pub fn read_tls_certificates() -> RedisResult<TlsCertificates> {
+        // Use system root certificates if no CA bundle is configured
+        let root_cert = match APP_CONF.redis.tls_ca_path {
+            Some(ref path) => Some(fs::read(path)?),
+            None => None,
+        };
+
+        // Enable client authentication (mTLS) if both a certificate and its key are configured
+        let client_tls = match (
+            &APP_CONF.redis.tls_client_cert_path,
+            &APP_CONF.redis.tls_client_key_path,
+        ) {
+            (Some(cert_path), Some(key_path)) => Some(ClientTlsConfig {
+                client_cert: fs::read(cert_path)?,
+                client_key: fs::read(key_path)?,
+            }),
+            _ => None,
+        };
+
+        Ok(TlsCertificates {
+            client_tls,
+            root_cert,
+        })
+    }
+
+    pub fn pool_builder<M: ManageConnection>() -> r2d2::Builder<M> {
+        Pool::builder()
+            .test_on_check_out(false)
+            .max_size(APP_CONF.redis.pool_size)
+            .max_lifetime(Some(Duration::from_secs(
+                APP_CONF.redis.max_lifetime_seconds,
+            )))
+            .idle_timeout(Some(Duration::from_secs(
+                APP_CONF.redis.idle_timeout_seconds,
+            )))
+            .connection_timeout(Duration::from_secs(
+                APP_CONF.redis.connection_timeout_seconds,
+            ))
+    }
+}
+
+impl CacheBackend for CacheBackendRedis {
+    fn get_meta(&self, shard: u8, key: &str) -> CacheBackendResult<Option<CacheBackendMeta>> {
+        self.read(|pool| get_cache_store_client_try!(pool, CacheStoreError::Disconnected, client {
+            match (*client).hget::<_, _, (Value, Value, Value, Value, Value)>(
+                key, (KEY_FINGERPRINT, KEY_TAGS, KEY_FRESH_UNTIL, KEY_STALE_UNTIL, KEY_VARY)
+            ) {
+                Ok(value) => {
+                    match value {
+                        (
+                            Value::BulkString(fingerprint_bytes),
+                            tags_bytes,
+                            fresh_until_bytes,
+                            stale_until_bytes,
+                            _,
+                        ) => {
+                            let tags = CacheBackendRedis::parse_tags(shard, tags_bytes);
+
+                            if !tags.is_empty() {
+                                // Proceed a soft bump of last access time of associated tag \
+                                //   keys. This prevents a frequently accessed cache namespace \
+                                //   to become 'orphan' (ie. one or more tag keys are \
+                                //   LRU-expired), and thus cache namespace not to be properly \
+                                //   removed on purge of an associated tag.
+                                // Also, count bumped keys. It may happen that some tag keys \
+                                //   are incorrectly removed by Redis LRU system, as it is \
+                                //   probabilistic and thus might sample some keys incorrectly.
+                                // The conditions explained above only happens on Redis \
+                                //   instances with used memory going over the threshold of \
+                                //   the max memory policy.
+                                let tags_count = tags.len();
+
+                                match redis::cmd("TOUCH").arg(&tags).query::<usize>(&mut *client) {
+                                    Ok(bump_count) => {
+                                        // Partial bump count? Consider cache as non-existing
+                                        if bump_count < tags_count {
+                                            info!(
+                                                "got only partial tag count: {}/{}",
+                                                bump_count, tags_count
+                                            );
+
+                                            return Ok(None);
+                                        }
+                                    },
+                                    Err(err) => {
+                                        error!("error bumping access time of tags: {}", err);
+                                    }
+                                }
+                            }
+
+                            // Decode raw bytes to string
+                            let fresh_until = CacheBackendRedis::parse_deadline(
+                                fresh_until_bytes
+                            );
+                            let stale_until = CacheBackendRedis::parse_deadline(
+                                stale_until_bytes
+                            );
+
+                            String::from_utf8(fingerprint_bytes).map_or(Err(CacheStoreError::Corrupted), |fingerprint| Ok(Some(CacheBackendMeta { fingerprint, tags, fresh_until, stale_until, vary: Vec::new() })))
+                        },
+                        (Value::Nil, _, _, _, Value::BulkString(vary_bytes)) => {
+                            Ok(Some(CacheBackendRedis::parse_vary_marker(vary_bytes)))
+                        },
+                        (Value::Nil, _, _, _, _) => Ok(None),
+                        _ => Err(CacheStoreError::Invalid),
+                    }
+                },
+                _ => Err(CacheStoreError::Failed),
+            }
+        }))
+    }
+
+    fn get_body(&self, key: &str) -> CacheBackendResult<Option<Vec<u8>>> {
+        self.read(|pool| get_cache_store_client_try!(pool, CacheStoreError::Disconnected, client {
+            (*client).hget::<_, _, Value>(key, KEY_BODY).map_or(Err(CacheStoreError::Failed), |value| match value {
+                Value::BulkString(body_bytes) => Ok(Some(body_bytes)),
+                Value::Nil => Ok(None),
+                _ => Err(CacheStoreError::Invalid),
+            })
+        }))
+    }
+
+    fn set(
+        &self,
+        key: &str,
+        key_mask: &str,
+        value: &[u8],
+        fingerprint: &str,
+        deadlines: &CacheStoreDeadlines,
+        key_tags: &[(String, String)],
+    ) -> CacheBackendResult<()> {
+        get_cache_store_client_try!(self.pool(), CacheStoreError::Disconnected, client {
+            let mut pipeline = redis::pipe();
+
+            // Append storage command
+            {
+                let key_tag_masks = key_tags.iter()
+                    .map(|key_tag| key_tag.1.as_ref())
+                    .collect::<Vec<&str>>();
+                let fresh_until_string = deadlines.fresh_until.to_string();
+                let stale_until_string = deadlines.stale_until.to_string();
+
+                pipeline.hset_multiple(
+                    key, &[
+                        (
+                            KEY_FINGERPRINT,
+                            fingerprint.as_bytes()
+                        ),
+
+                        (
+                            KEY_TAGS,
+                            key_tag_masks.join(KEY_TAGS_SEPARATOR).as_bytes()
+                        ),
+
+                        (
+                            KEY_FRESH_UNTIL,
+                            fresh_until_string.as_bytes()
+                        ),
+
+                        (
+                            KEY_STALE_UNTIL,
+                            stale_until_string.as_bytes()
+                        ),
+
+                        (
+                            KEY_BODY,
+                            value
+                        )
+                    ]
+                ).ignore();
+            }
+
+            pipeline.expire(key, safe_usize_to_i64(deadlines.expire_after)).ignore();
+
+            for key_tag in key_tags {
+                pipeline.sadd(&key_tag.0, key_mask).ignore();
+                pipeline.expire(&key_tag.0, safe_usize_to_i64(APP_CONF.redis.max_key_expiration));
+            }
+
+            // Bucket (MULTI operation for main data + bucket marker)
+            match pipeline.query::<()>(&mut *client) {
+                Ok(()) => Ok(()),
+                Err(err) => {
+                    error!("got store error: {}", err);
+
+                    Err(CacheStoreError::Failed)
+                }
+            }
+        })
+    }
+
+    fn set_vary(&self, key: &str, vary: &[String], ttl: usize) -> CacheBackendResult<()> {
+        get_cache_store_client_try!(self.pool(), CacheStoreError::Disconnected, client {
+            // Replace any value stored before the route started to vary
+            redis::pipe()
+                .atomic()
+                .del(key).ignore()
+                .hset(key, KEY_VARY, vary.join(KEY_VARY_SEPARATOR)).ignore()
+                .expire(key, safe_usize_to_i64(ttl)).ignore()
+                .query::<()>(&mut *client)
+                .or(Err(CacheStoreError::Failed))
+        })
+    }
+
+    fn acquire_lock(&self, key: &str, token: &str, ttl_seconds: u64) -> CacheBackendResult<bool> {
+        get_cache_store_client_try!(self.pool(), CacheStoreError::Disconnected, client {
+            redis::cmd("SET")
+                .arg(key)
+                .arg(token)
+                .arg("NX")
+                .arg("EX")
+                .arg(ttl_seconds)
+                .query::<Option<String>>(&mut *client)
+                .map(|reply| reply.is_some())
+                .or(Err(CacheStoreError::Failed))
+        })
+    }
+
+    fn release_lock(&self, key: &str, token: &str) -> CacheBackendResult<()> {
+        get_cache_store_client_try!(self.pool(), CacheStoreError::Disconnected, client {
+            redis::Script::new(LOCK_RELEASE_SCRIPT)
+                .key(key)
+                .arg(token)
+                .invoke::<()>(&mut *client)
+                .or(Err(CacheStoreError::Failed))
+        })
+    }
+
+    fn has_lock(&self, key: &str) -> CacheBackendResult<bool> {
+        get_cache_store_client_try!(self.pool(), CacheStoreError::Disconnected, client {
+            (*client).exists::<_, bool>(key).or(Err(CacheStoreError::Failed))
+        })
+    }
+
+    fn purge_tag(
+        &self,
+        variant: &CachePurgeVariant,
+        shard: u8,
+        key_tag: &str,
+    ) -> CacheBackendResult<()> {
+        get_cache_store_client_wait!(self.pool(), CacheStoreError::Disconnected, client {
+            match variant.get_script() {
+                // Invoke keyspace cleanup script for key tag
+                Some(script) => {
+                    let result = redis::Script::new(script)
+                        .arg(ROUTE_PREFIX)
+                        .arg(shard)
+                        .arg(key_tag)
+                        .invoke::<()>(&mut *client);
+
+                    result
+                        .and(Ok(()))
+                        .or(Err(CacheStoreError::Failed))
+                }
+                None => Self::mark_stale_tag(&mut client, shard, key_tag),
+            }
+        })
+    }
+
+    fn publish_invalidation(&self, key_tag: &str) -> CacheBackendResult<()> {
+        get_cache_store_client_wait!(self.pool(), CacheStoreError::Disconnected, client {
+            (*client)
+                .publish::<_, _, ()>(&*INVALIDATION_CHANNEL, key_tag)
+                .or(Err(CacheStoreError::Failed))
+        })
+    }
+
+    fn subscribe_invalidations(&self, handler: CacheBackendInvalidationHandler) {
+        let target = self.target.clone();
+
+        // Always subscribe to the current primary, which may change over time
+        Self::spawn_invalidations_listener(
+            move || {
+                Some(
+                    target
+                        .read()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .client
+                        .clone(),
+                )
+            },
+            handler,
+        );
+    }
+}
+
+impl CacheBackendRedis {
+    fn read<T, F>(&self, reader: F) -> CacheBackendResult<T>
+    where
+        F: Fn(Pool<redis::Client>) -> CacheBackendResult<T>,
+    {
+        // Spread reads over replicas in a round-robin fashion, if any
+        // Notice: replicas may lag behind the primary, which can only cause extra cache misses \
+        //   right after a write, or hits on a purged key for the duration of the lag.
+        // Notice: replicas that recently failed are skipped until their backoff window closes, \
+        //   so that a dead replica does not add a failed round-trip to every other read.
+        if let Some(index) = self.replicas_health.next(CacheStoreMeta::now()) {
+            match reader(self.replicas[index].clone()) {
+                Err(CacheStoreError::Disconnected) | Err(CacheStoreError::Failed) => {
+                    warn!(
+                        "could not read from redis replica #{}, using primary (marked down for {}s)",
+                        index, REPLICA_DOWN_BACKOFF_SECONDS
+                    );
+
+                    self.replicas_health.mark_down(index, CacheStoreMeta::now());
+                }
+                result => return result,
+            }
+        }
+
+        reader(self.pool())
+    }
+
+    fn mark_stale_tag(
+        client: &mut redis::Connection,
+        shard: u8,
+        key_tag: &str,
+    ) -> CacheBackendResult<()> {
+        let now = CacheStoreMeta::now();
+
+        Self::load_stale_mark(client)?;
+
+        // Members are scanned in batches, and their cache keys marked as stale in pipelines
+        let mut cursor = 0;
+
+        loop {
+            let (next_cursor, tag_masks) = redis::cmd("SSCAN")
+                .arg(key_tag)
+                .arg(cursor)
+                .arg("COUNT")
+                .arg(PURGE_BATCH_SIZE)
+                .query::<(u64, Vec<String>)>(client)
+                .or(Err(CacheStoreError::Failed))?;
+
+            if !tag_masks.is_empty() {
+                let mut pipeline = redis::pipe();
+
+                for tag_mask in &tag_masks {
+                    pipeline
+                        .add_command(Self::gen_stale_mark_command(
+                            &format!("{ROUTE_PREFIX}:{shard}:c:{tag_mask}"),
+                            now,
+                        ))
+                        .ignore();
+                }
+
+                pipeline
+                    .query::<()>(client)
+                    .or(Err(CacheStoreError::Failed))?;
+            }
+
+            if next_cursor == 0 {
+                return Ok(());
+            }
+
+            cursor = next_cursor;
+        }
+    }
+
+    pub fn load_stale_mark(client: &mut dyn ConnectionLike) -> CacheBackendResult<()> {
+        // Notice: scripts get loaded on all nodes of a cluster, as their keys live on any node
+        STALE_MARK_SCRIPT
+            .prepare_invoke()
+            .load(client)
+            .and(Ok(()))
+            .or(Err(CacheStoreError::Failed))
+    }
+
+    pub fn gen_stale_mark_command(key: &str, now: u64) -> Cmd {
+        let mut command = redis::cmd("EVALSHA");
+
+        command
+            .arg(STALE_MARK_SCRIPT.get_hash())
+            .arg(1)
+            .arg(key)
+            .arg(now);
+
+        command
+    }
+
+    fn pool(&self) -> Pool<redis::Client> {
+        // Pools are reference-counted, cloning them is cheap and releases the lock right away
+        self.target
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pool
+            .clone()
+    }
+
+    pub fn parse_tags(shard: u8, tags_bytes: Value) -> Vec<String> {
+        if let Value::BulkString(tags_bytes_data) = tags_bytes {
+            if let Ok(tags_data) = String::from_utf8(tags_bytes_data) {
+                if !tags_data.is_empty() {
+                    return tags_data
+                        .split(KEY_TAGS_SEPARATOR)
+                        .map(|tag| format!("{ROUTE_PREFIX}:{shard}:{tag}"))
+                        .collect();
+                }
+            }
+        }
+
+        Vec::new()
+    }
+
+    pub fn parse_vary_marker(vary_bytes: Vec<u8>) -> CacheBackendMeta {
+        // Vary markers only list the request headers that the route varies on
+        CacheBackendMeta {
+            fingerprint: String::new(),
+            tags: Vec::new(),
+            fresh_until: 0,
+            stale_until: 0,
+            vary: String::from_utf8_lossy(&vary_bytes)
+                .split(KEY_VARY_SEPARATOR)
+                .filter(|header| !header.is_empty())
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+
+    pub fn parse_deadline(deadline_bytes: Value) -> u64 {
+        // Entries stored without a deadline get a zero one (see how meta handles this)
+        redis::from_redis_value::<Option<u64>>(&deadline_bytes)
+            .ok()
+            .flatten()
+            .unwrap_or(0)
+    }
+
+    pub fn spawn_invalidations_listener<F>(
+        mut next_client: F,
+        handler: CacheBackendInvalidationHandler,
+    ) where
+        F: FnMut() -> Option<redis::Client> + Send + 'static,
+    {
+        thread::Builder::new()
+            .name(THREAD_NAME_CACHE_INVALIDATION.to_string())
+            .spawn(move || {
+                // Pick a new client upon each reconnect, in case the current one goes down
+                loop {
+                    if let Some(client) = next_client() {
+                        if let Err(err) = Self::listen_invalidations(&client, &handler) {
+                            warn!("lost cache invalidation subscription because: {}", err);
+                        }
+                    }
+
+                    // Prevents reconnect loop floods
+                    thread::sleep(Duration::from_secs(1));
+                }
+            })
+            .ok();
+    }
+
+    fn listen_invalidations(
+        client: &redis::Client,
+        handler: &CacheBackendInvalidationHandler,
+    ) -> RedisResult<()> {
+        let mut connection = client.get_connection()?;
+        let mut pubsub = connection.as_pubsub();
+
+        pubsub.subscribe(&*INVALIDATION_CHANNEL)?;
+
+        info!("subscribed to cache invalidations");
+
+        // Invalidations may have been missed while not subscribed, start over from scratch
+        handler(CacheBackendInvalidation::All);
+
+        loop {
+            let key_tag = pubsub.get_message()?.get_payload::<String>()?;
+
+            debug!("got cache invalidation for tag: {}", key_tag);
+
+            handler(CacheBackendInvalidation::Tag(key_tag));
+        }
+    }
+}
+
+impl CacheBackendRedisReplicasHealth {
+    pub fn new(size: usize) -> Self {
+        CacheBackendRedisReplicasHealth {
+            cursor: AtomicUsize::new(0),
+            down_until: (0..size).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn next(&self, now: u64) -> Option<usize> {
+        let size = self.down_until.len();
+
+        // Pick replicas in a round-robin fashion, skipping those that are marked down
+        (0..size)
+            .map(|_| self.cursor.fetch_add(1, Ordering::Relaxed) % size)
+            .find(|index| self.down_until[*index].load(Ordering::Relaxed) <= now)
+    }
+
+    pub fn mark_down(&self, index: usize, now: u64) {
+        if let Some(down_until) = self.down_until.get(index) {
+            down_until.store(now + REPLICA_DOWN_BACKOFF_SECONDS, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_node_addresses() {
+        assert_eq!(
+            CacheBackendRedisBuilder::parse_node("10.0.0.2:6379"),
+            Some(("10.0.0.2".to_string(), 6379))
+        );
+        assert_eq!(
+            CacheBackendRedisBuilder::parse_node("[::1]:6380"),
+            Some(("::1".to_string(), 6380))
+        );
+        assert_eq!(CacheBackendRedisBuilder::parse_node("localhost"), None);
+        assert_eq!(
+            CacheBackendRedisBuilder::parse_node("localhost:redis"),
+            None
+        );
+    }
+
+    #[test]
+    fn it_skips_failed_replicas() {
+        let health = CacheBackendRedisReplicasHealth::new(2);
+
+        assert_eq!(health.next(100), Some(0));
+        assert_eq!(health.next(100), Some(1));
+
+        health.mark_down(0, 100);
+
+        assert_eq!(health.next(100), Some(1));
+        assert_eq!(health.next(101), Some(1));
+        assert_eq!(health.next(100 + REPLICA_DOWN_BACKOFF_SECONDS), Some(0));
+
+        health.mark_down(0, 200);
+        health.mark_down(1, 200);
+
+        assert_eq!(health.next(200), None);
+        assert_eq!(CacheBackendRedisReplicasHealth::new(0).next(200), None);
+    }
+}
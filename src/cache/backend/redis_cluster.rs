@@ -0,0 +1,375 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use r2d2::Pool;
+use redis::cluster::{cluster_pipe, ClusterClient, ClusterClientBuilder};
+use redis::{self, Commands, ConnectionInfo, RedisResult, Value};
+use std::time::Duration;
+
+use super::redis::{
+    safe_usize_to_i64, CacheBackendRedis, CacheBackendRedisBuilder, INVALIDATION_CHANNEL, KEY_BODY,
+    KEY_FINGERPRINT, KEY_FRESH_UNTIL, KEY_STALE_UNTIL, KEY_TAGS, KEY_TAGS_SEPARATOR, KEY_VARY,
+    KEY_VARY_SEPARATOR, LOCK_RELEASE_SCRIPT, PURGE_BATCH_SIZE,
+};
+use super::{CacheBackend, CacheBackendInvalidationHandler, CacheBackendMeta, CacheBackendResult};
+use crate::cache::route::ROUTE_PREFIX;
+use crate::cache::store::{
+    CachePurgeVariant, CacheStoreDeadlines, CacheStoreError, CacheStoreMeta,
+};
+use crate::APP_CONF;
+
+pub struct CacheBackendRedisClusterBuilder;
+
+pub struct CacheBackendRedisCluster {
+    nodes: Vec<String>,
+    pool: Pool<ClusterClient>,
+}
+
+impl CacheBackendRedisClusterBuilder {
+    pub fn create() -> CacheBackendRedisCluster {
+        // Use configured host as the cluster seed node, if no seed node is configured
+        let nodes = if APP_CONF.redis.cluster_nodes.is_empty() {
+            vec![format!("{}:{}", APP_CONF.redis.host, APP_CONF.redis.port)]
+        } else {
+            APP_CONF.redis.cluster_nodes.to_owned()
+        };
+
+        info!(
+            "binding to redis cluster store backend at {}",
+            nodes.join(", ")
+        );
+
+        if APP_CONF.redis.database != 0 {
+            warn!("redis cluster only has database 0, ignoring configured database");
+        }
+
+        // Credentials and TLS mode get inherited from seed nodes
+        let nodes_info = nodes
+            .iter()
+            .map(|node| CacheBackendRedisBuilder::gen_node_connection_info(node, true))
+            .collect::<RedisResult<Vec<ConnectionInfo>>>()
+            .expect("invalid redis cluster node address");
+
+        let mut builder = ClusterClientBuilder::new(nodes_info).connection_timeout(
+            Duration::from_secs(APP_CONF.redis.connection_timeout_seconds),
+        );
+
+        if APP_CONF.redis.tls {
+            match CacheBackendRedisBuilder::read_tls_certificates() {
+                Ok(certificates) => builder = builder.certs(certificates),
+                Err(e) => panic!("could not read redis tls certificates: {e}"),
+            }
+        }
+
+        match builder.build() {
+            Ok(manager) => match CacheBackendRedisBuilder::pool_builder().build(manager) {
+                Ok(pool) => {
+                    info!("bound to redis cluster store backend");
+
+                    CacheBackendRedisCluster { nodes, pool }
+                }
+                Err(e) => panic!("could not spawn redis cluster pool: {e}"),
+            },
+            Err(e) => panic!("could not create redis cluster connection manager: {e}"),
+        }
+    }
+}
+
+impl CacheBackendRedisCluster {
+    pub fn gen_slot_key(key: &str) -> String {
+        // Cache keys get their mask enclosed in a hash tag, so that the cluster slot of a \
+        //   cache key can always be derived from the mask stored in tag sets. Tag sets are \
+        //   spread over slots on their own, and are thus purged member by member.
+        match key.splitn(4, ':').collect::<Vec<&str>>()[..] {
+            [prefix, shard, "c", mask] if !mask.starts_with('{') => {
+                format!("{prefix}:{shard}:c:{{{mask}}}")
+            }
+            _ => key.to_string(),
+        }
+    }
+}
+
+impl CacheBackend for CacheBackendRedisCluster {
+    fn get_meta(&self, shard: u8, key: &str) -> CacheBackendResult<Option<CacheBackendMeta>> {
+        get_cache_store_client_try!(self.pool, CacheStoreError::Disconnected, client {
+            match (*client).hget::<_, _, (Value, Value, Value, Value, Value)>(
+                Self::gen_slot_key(key),
+                (KEY_FINGERPRINT, KEY_TAGS, KEY_FRESH_UNTIL, KEY_STALE_UNTIL, KEY_VARY)
+            ) {
+                Ok((
+                    Value::BulkString(fingerprint_bytes), tags_bytes, fresh_until_bytes,
+                    stale_until_bytes, _
+                )) => {
+                    let tags = CacheBackendRedis::parse_tags(shard, tags_bytes);
+
+                    if !tags.is_empty() {
+                        // Tag keys live on different slots, thus they cannot be bumped \
+                        //   using a single 'TOUCH' command (see standalone backend for \
+                        //   why tags get bumped)
+                        let mut pipeline = cluster_pipe();
+
+                        for tag in &tags {
+                            pipeline.cmd("TOUCH").arg(tag);
+                        }
+
+                        match pipeline.query::<Vec<usize>>(&mut client) {
+                            Ok(bump_counts) => {
+                                let bump_count = bump_counts.iter().sum::<usize>();
+
+                                if bump_count < tags.len() {
+                                    info!(
+                                        "got only partial tag count: {}/{}",
+                                        bump_count, tags.len()
+                                    );
+
+                                    return Ok(None);
+                                }
+                            },
+                            Err(err) => {
+                                error!("error bumping access time of tags: {}", err);
+                            }
+                        }
+                    }
+
+                    let fresh_until = CacheBackendRedis::parse_deadline(fresh_until_bytes);
+                    let stale_until = CacheBackendRedis::parse_deadline(stale_until_bytes);
+
+                    String::from_utf8(fingerprint_bytes).map_or(
+                        Err(CacheStoreError::Corrupted),
+                        |fingerprint| Ok(Some(CacheBackendMeta {
+                            fingerprint, tags, fresh_until, stale_until, vary: Vec::new()
+                        }))
+                    )
+                },
+                Ok((Value::Nil, _, _, _, Value::BulkString(vary_bytes))) => {
+                    Ok(Some(CacheBackendRedis::parse_vary_marker(vary_bytes)))
+                },
+                Ok((Value::Nil, _, _, _, _)) => Ok(None),
+                Ok(_) => Err(CacheStoreError::Invalid),
+                Err(_) => Err(CacheStoreError::Failed),
+            }
+        })
+    }
+
+    fn get_body(&self, key: &str) -> CacheBackendResult<Option<Vec<u8>>> {
+        get_cache_store_client_try!(self.pool, CacheStoreError::Disconnected, client {
+            match (*client).hget::<_, _, Value>(Self::gen_slot_key(key), KEY_BODY) {
+                Ok(Value::BulkString(body_bytes)) => Ok(Some(body_bytes)),
+                Ok(Value::Nil) => Ok(None),
+                Ok(_) => Err(CacheStoreError::Invalid),
+                Err(_) => Err(CacheStoreError::Failed),
+            }
+        })
+    }
+
+    fn set(
+        &self,
+        key: &str,
+        key_mask: &str,
+        value: &[u8],
+        fingerprint: &str,
+        deadlines: &CacheStoreDeadlines,
+        key_tags: &[(String, String)],
+    ) -> CacheBackendResult<()> {
+        get_cache_store_client_try!(self.pool, CacheStoreError::Disconnected, client {
+            let slot_key = Self::gen_slot_key(key);
+            let mut pipeline = cluster_pipe();
+
+            let key_tag_masks = key_tags.iter()
+                .map(|key_tag| key_tag.1.as_ref())
+                .collect::<Vec<&str>>();
+            let fresh_until_string = deadlines.fresh_until.to_string();
+            let stale_until_string = deadlines.stale_until.to_string();
+
+            pipeline.hset_multiple(
+                &slot_key, &[
+                    (KEY_FINGERPRINT, fingerprint.as_bytes()),
+                    (KEY_TAGS, key_tag_masks.join(KEY_TAGS_SEPARATOR).as_bytes()),
+                    (KEY_FRESH_UNTIL, fresh_until_string.as_bytes()),
+                    (KEY_STALE_UNTIL, stale_until_string.as_bytes()),
+                    (KEY_BODY, value)
+                ]
+            ).ignore();
+
+            pipeline.expire(&slot_key, safe_usize_to_i64(deadlines.expire_after)).ignore();
+
+            for key_tag in key_tags {
+                pipeline.sadd(&key_tag.0, key_mask).ignore();
+                pipeline.expire(
+                    &key_tag.0, safe_usize_to_i64(APP_CONF.redis.max_key_expiration)
+                ).ignore();
+            }
+
+            // Notice: cluster pipelines are not atomic, as commands are routed to multiple nodes
+            match pipeline.query::<()>(&mut client) {
+                Ok(()) => Ok(()),
+                Err(err) => {
+                    error!("got store error: {}", err);
+
+                    Err(CacheStoreError::Failed)
+                }
+            }
+        })
+    }
+
+    fn set_vary(&self, key: &str, vary: &[String], ttl: usize) -> CacheBackendResult<()> {
+        get_cache_store_client_try!(self.pool, CacheStoreError::Disconnected, client {
+            let slot_key = Self::gen_slot_key(key);
+
+            // Replace any value stored before the route started to vary
+            let mut pipeline = cluster_pipe();
+
+            pipeline.del(&slot_key).ignore();
+            pipeline.hset(&slot_key, KEY_VARY, vary.join(KEY_VARY_SEPARATOR)).ignore();
+            pipeline.expire(&slot_key, safe_usize_to_i64(ttl)).ignore();
+
+            pipeline.query::<()>(&mut client).or(Err(CacheStoreError::Failed))
+        })
+    }
+
+    fn acquire_lock(&self, key: &str, token: &str, ttl_seconds: u64) -> CacheBackendResult<bool> {
+        get_cache_store_client_try!(self.pool, CacheStoreError::Disconnected, client {
+            redis::cmd("SET")
+                .arg(key)
+                .arg(token)
+                .arg("NX")
+                .arg("EX")
+                .arg(ttl_seconds)
+                .query::<Option<String>>(&mut *client)
+                .map(|reply| reply.is_some())
+                .or(Err(CacheStoreError::Failed))
+        })
+    }
+
+    fn release_lock(&self, key: &str, token: &str) -> CacheBackendResult<()> {
+        get_cache_store_client_try!(self.pool, CacheStoreError::Disconnected, client {
+            redis::Script::new(LOCK_RELEASE_SCRIPT)
+                .key(key)
+                .arg(token)
+                .invoke::<()>(&mut *client)
+                .or(Err(CacheStoreError::Failed))
+        })
+    }
+
+    fn has_lock(&self, key: &str) -> CacheBackendResult<bool> {
+        get_cache_store_client_try!(self.pool, CacheStoreError::Disconnected, client {
+            (*client).exists::<_, bool>(key).or(Err(CacheStoreError::Failed))
+        })
+    }
+
+    fn purge_tag(
+        &self,
+        variant: &CachePurgeVariant,
+        shard: u8,
+        key_tag: &str,
+    ) -> CacheBackendResult<()> {
+        let now = CacheStoreMeta::now();
+
+        get_cache_store_client_wait!(self.pool, CacheStoreError::Disconnected, client {
+            // Cache keys listed in a tag set live on other slots than the tag set itself, thus \
+            //   the purge script cannot be used there. Members are scanned from the tag set \
+            //   node, and their cache keys get unlinked in batches routed to their own node.
+            // Notice: a cache key stored while the tag set is purged may be left behind, \
+            //   though it would not be served anymore as one of its tags would be missing.
+            if variant.is_soft() {
+                CacheBackendRedis::load_stale_mark(&mut *client)?;
+            }
+
+            let mut cursor = 0;
+
+            loop {
+                let (next_cursor, tag_masks) = redis::cmd("SSCAN")
+                    .arg(key_tag)
+                    .arg(cursor)
+                    .arg("COUNT")
+                    .arg(PURGE_BATCH_SIZE)
+                    .query::<(u64, Vec<String>)>(&mut *client)
+                    .or(Err(CacheStoreError::Failed))?;
+
+                if !tag_masks.is_empty() {
+                    let mut pipeline = cluster_pipe();
+
+                    for tag_mask in &tag_masks {
+                        let key = Self::gen_slot_key(
+                            &format!("{ROUTE_PREFIX}:{shard}:c:{tag_mask}")
+                        );
+
+                        // Soft purges mark cache keys as stale instead, each on its own node
+                        if variant.is_soft() {
+                            pipeline
+                                .add_command(CacheBackendRedis::gen_stale_mark_command(&key, now))
+                                .ignore();
+                        } else {
+                            pipeline.unlink(key).ignore();
+                        }
+                    }
+
+                    pipeline.query::<()>(&mut client).or(Err(CacheStoreError::Failed))?;
+                }
+
+                if next_cursor == 0 {
+                    break;
+                }
+
+                cursor = next_cursor;
+            }
+
+            // Soft purges keep the tag set, as marked cache keys must remain valid
+            if variant.is_soft() {
+                return Ok(());
+            }
+
+            (*client).unlink::<_, ()>(key_tag).or(Err(CacheStoreError::Failed))
+        })
+    }
+
+    fn publish_invalidation(&self, key_tag: &str) -> CacheBackendResult<()> {
+        get_cache_store_client_wait!(self.pool, CacheStoreError::Disconnected, client {
+            // Messages published on any node get broadcasted to the whole cluster
+            (*client)
+                .publish::<_, _, ()>(&*INVALIDATION_CHANNEL, key_tag)
+                .or(Err(CacheStoreError::Failed))
+        })
+    }
+
+    fn subscribe_invalidations(&self, handler: CacheBackendInvalidationHandler) {
+        let clients = self
+            .nodes
+            .iter()
+            .filter_map(|node| CacheBackendRedisBuilder::open_node_client(node, true).ok())
+            .collect::<Vec<redis::Client>>();
+
+        // Rotate over all known nodes, in case the current one goes down
+        let mut clients_cycle = clients.into_iter().cycle();
+
+        CacheBackendRedis::spawn_invalidations_listener(move || clients_cycle.next(), handler);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_generates_slot_keys() {
+        assert_eq!(
+            CacheBackendRedisCluster::gen_slot_key("bloom:0:c:dc56d17a:7cf7a048e7a274fb"),
+            "bloom:0:c:{dc56d17a:7cf7a048e7a274fb}"
+        );
+        assert_eq!(
+            CacheBackendRedisCluster::gen_slot_key("bloom:0:c:{dc56d17a:7cf7a048e7a274fb}"),
+            "bloom:0:c:{dc56d17a:7cf7a048e7a274fb}"
+        );
+        assert_eq!(
+            CacheBackendRedisCluster::gen_slot_key("bloom:7:b:2eb6c00c"),
+            "bloom:7:b:2eb6c00c"
+        );
+        assert_eq!(
+            CacheBackendRedisCluster::gen_slot_key("bloom:7:a:dc56d17a"),
+            "bloom:7:a:dc56d17a"
+        );
+    }
+}
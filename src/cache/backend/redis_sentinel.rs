@@ -0,0 +1,148 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use redis::{self, ConnectionAddr, Value};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use super::redis::{CacheBackendRedisBuilder, CacheBackendRedisTarget};
+use crate::{APP_CONF, THREAD_NAME_CACHE_SENTINEL};
+
+pub struct CacheBackendRedisSentinel {
+    nodes: Vec<String>,
+    master_name: String,
+}
+
+impl CacheBackendRedisSentinel {
+    pub fn new() -> Self {
+        // Use configured host as the only sentinel, if no sentinel is configured
+        let nodes = if APP_CONF.redis.sentinel_nodes.is_empty() {
+            vec![format!("{}:{}", APP_CONF.redis.host, APP_CONF.redis.port)]
+        } else {
+            APP_CONF.redis.sentinel_nodes.to_owned()
+        };
+
+        CacheBackendRedisSentinel {
+            nodes,
+            master_name: APP_CONF.redis.sentinel_master_name.to_owned(),
+        }
+    }
+
+    pub fn discover_primary(&self) -> Option<ConnectionAddr> {
+        // Ask sentinels one after the other, the first one to answer wins
+        for node in &self.nodes {
+            match self.query_primary(node) {
+                Ok(Some((host, port))) => {
+                    debug!(
+                        "sentinel {} reported redis primary at {}:{}",
+                        node, host, port
+                    );
+
+                    return Some(CacheBackendRedisBuilder::gen_node_addr(host, port));
+                }
+                Ok(None) => warn!(
+                    "sentinel {} does not know redis primary: {}",
+                    node, self.master_name
+                ),
+                Err(err) => warn!("could not query sentinel {} because: {}", node, err),
+            }
+        }
+
+        None
+    }
+
+    pub fn watch(self, target: Arc<RwLock<CacheBackendRedisTarget>>) {
+        thread::Builder::new()
+            .name(THREAD_NAME_CACHE_SENTINEL.to_string())
+            .spawn(move || loop {
+                thread::sleep(Duration::from_secs(
+                    APP_CONF.redis.sentinel_check_interval_seconds,
+                ));
+
+                let primary = match self.discover_primary() {
+                    Some(primary) => primary,
+                    None => {
+                        error!("could not discover redis primary from any sentinel");
+
+                        continue;
+                    }
+                };
+
+                let current = target
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .addr
+                    .to_owned();
+
+                if primary != current {
+                    warn!(
+                        "redis primary failed over from {} to {}, rebuilding pool",
+                        current, primary
+                    );
+
+                    // Connect to the new primary first, as the current pool must stay in use \
+                    //   until the new one is ready (a failed attempt is retried on next check).
+                    let primary_name = primary.to_string();
+
+                    match CacheBackendRedisBuilder::connect(primary) {
+                        Ok(new_target) => {
+                            *target.write().unwrap_or_else(PoisonError::into_inner) = new_target;
+
+                            info!("bound to new redis primary at {}", primary_name);
+                        }
+                        Err(err) => error!("could not bind to new redis primary: {}", err),
+                    }
+                }
+            })
+            .ok();
+    }
+
+    fn query_primary(&self, node: &str) -> redis::RedisResult<Option<(String, u16)>> {
+        let client = CacheBackendRedisBuilder::open_node_client(node, false)?;
+        let mut connection = client.get_connection_with_timeout(Duration::from_secs(
+            APP_CONF.redis.connection_timeout_seconds,
+        ))?;
+
+        let reply = redis::cmd("SENTINEL")
+            .arg("get-master-addr-by-name")
+            .arg(&self.master_name)
+            .query::<Value>(&mut connection)?;
+
+        Ok(Self::parse_primary(reply))
+    }
+
+    fn parse_primary(reply: Value) -> Option<(String, u16)> {
+        // Sentinel replies with a '[host, port]' pair, or nil if the master name is unknown
+        redis::from_redis_value::<Option<(String, u16)>>(&reply)
+            .ok()
+            .flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_primary_addresses() {
+        assert_eq!(
+            CacheBackendRedisSentinel::parse_primary(Value::Array(vec![
+                Value::BulkString(b"10.0.0.2".to_vec()),
+                Value::BulkString(b"6379".to_vec()),
+            ])),
+            Some(("10.0.0.2".to_string(), 6379))
+        );
+        assert_eq!(
+            CacheBackendRedisSentinel::parse_primary(Value::Array(vec![
+                Value::BulkString(b"::1".to_vec()),
+                Value::BulkString(b"6380".to_vec()),
+            ])),
+            Some(("::1".to_string(), 6380))
+        );
+        assert_eq!(CacheBackendRedisSentinel::parse_primary(Value::Nil), None);
+    }
+}
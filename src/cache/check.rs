@@ -4,78 +4,167 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
+use hyper::header::{CacheControl, CacheDirective, ContentLength, SetCookie, Vary};
 use hyper::{Headers, Method, StatusCode};
 
+use crate::config::config::ConfigCacheRule;
 use crate::header::response_ignore::HeaderResponseBloomResponseIgnore;
+use crate::APP_CONF;
 
 pub struct CacheCheck;
 
 impl CacheCheck {
-    pub const fn from_request(method: &Method) -> bool {
-        Self::is_cacheable_method(method)
+    pub fn from_request(method: &Method, rule: Option<&ConfigCacheRule>) -> bool {
+        Self::is_cacheable_method(method, rule)
     }
 
-    pub fn from_response(method: &Method, status: StatusCode, headers: &Headers) -> bool {
-        Self::is_cacheable_method(method)
+    pub fn from_request_body(method: &Method, rule: &ConfigCacheRule, headers: &Headers) -> bool {
+        // Only buffer request bodies of known size, up to the configured limit
+        *method == Method::Post
+            && rule.post
+            && headers
+                .get::<ContentLength>()
+                .is_some_and(|content_length| content_length.0 <= rule.post_max_body_size)
+    }
+
+    pub fn from_response(
+        method: &Method,
+        rule: Option<&ConfigCacheRule>,
+        status: StatusCode,
+        headers: &Headers,
+    ) -> bool {
+        Self::is_cacheable_method(method, rule)
             && Self::is_cacheable_status(status)
             && Self::is_cacheable_response(headers)
     }
 
-    const fn is_cacheable_method(method: &Method) -> bool {
-        matches!(*method, Method::Get | Method::Head | Method::Options)
+    pub fn from_policy(rule: Option<&ConfigCacheRule>, ttl: usize, body_size: usize) -> bool {
+        // A TTL of zero means that the response is already expired, and thus never cached \
+        //   (eg. a status-specific TTL of zero, 'max-age=0' or a past 'Expires')
+        ttl > 0
+            && rule.is_none_or(|rule| {
+                !rule.bypass
+                    && rule
+                        .max_body_size
+                        .is_none_or(|max_body_size| body_size <= max_body_size)
+            })
     }
 
-    const fn is_cacheable_status(status: StatusCode) -> bool {
-        matches!(
-            status,
-            StatusCode::Ok
-                | StatusCode::NonAuthoritativeInformation
-                | StatusCode::NoContent
-                | StatusCode::ResetContent
-                | StatusCode::PartialContent
-                | StatusCode::MultiStatus
-                | StatusCode::AlreadyReported
-                | StatusCode::MultipleChoices
-                | StatusCode::MovedPermanently
-                | StatusCode::Found
-                | StatusCode::SeeOther
-                | StatusCode::PermanentRedirect
-                | StatusCode::Unauthorized
-                | StatusCode::PaymentRequired
-                | StatusCode::Forbidden
-                | StatusCode::NotFound
-                | StatusCode::MethodNotAllowed
-                | StatusCode::Gone
-                | StatusCode::UriTooLong
-                | StatusCode::UnsupportedMediaType
-                | StatusCode::RangeNotSatisfiable
-                | StatusCode::ExpectationFailed
-                | StatusCode::ImATeapot
-                | StatusCode::Locked
-                | StatusCode::FailedDependency
-                | StatusCode::PreconditionRequired
-                | StatusCode::RequestHeaderFieldsTooLarge
-                | StatusCode::NotImplemented
-                | StatusCode::NotExtended
-        )
+    fn is_cacheable_method(method: &Method, rule: Option<&ConfigCacheRule>) -> bool {
+        // Notice: POST requests are read requests on some routes (eg. GraphQL or search), \
+        //   though they are only cacheable if their body got hashed into the cache key.
+        match *method {
+            Method::Get | Method::Head | Method::Options => true,
+            Method::Post => rule.is_some_and(|rule| rule.post),
+            _ => false,
+        }
+    }
+
+    fn is_cacheable_status(status: StatusCode) -> bool {
+        APP_CONF.cache.statuses.contains(&u16::from(status))
     }
 
     fn is_cacheable_response(headers: &Headers) -> bool {
-        // Ignore responses with 'Bloom-Response-Ignore'
+        // Ignore responses with 'Bloom-Response-Ignore', or varying on anything ('Vary: *')
         !headers.has::<HeaderResponseBloomResponseIgnore>()
+            && !matches!(headers.get::<Vary>(), Some(Vary::Any))
+            && (!APP_CONF.cache.honor_http_caching || Self::is_cacheable_http_response(headers))
+    }
+
+    fn is_cacheable_http_response(headers: &Headers) -> bool {
+        // Responses setting cookies are specific to their user, and thus never shared
+        if headers.has::<SetCookie>() {
+            return false;
+        }
+
+        // Notice: directives with a field name argument (eg. 'private="Set-Cookie"') are \
+        //   parsed as extensions, though they are handled the same way as bare directives.
+        headers.get::<CacheControl>().is_none_or(|cache_control| {
+            !cache_control
+                .iter()
+                .any(|cache_directive| match cache_directive {
+                    CacheDirective::NoStore | CacheDirective::NoCache | CacheDirective::Private => {
+                        true
+                    }
+                    CacheDirective::Extension(name, _) => {
+                        name.eq_ignore_ascii_case("private")
+                            || name.eq_ignore_ascii_case("no-cache")
+                    }
+                    _ => false,
+                })
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cache::ttl::CacheTTL;
+    use regex::Regex;
+    use std::collections::HashMap;
 
     #[test]
     fn it_asserts_valid_cacheable_method() {
-        assert!(CacheCheck::is_cacheable_method(&Method::Get), "GET");
-        assert!(CacheCheck::is_cacheable_method(&Method::Head), "HEAD");
-        assert!(CacheCheck::is_cacheable_method(&Method::Options), "OPTIONS");
-        assert!(!CacheCheck::is_cacheable_method(&Method::Post), "POST");
+        let mut rule = ConfigCacheRule {
+            methods: Vec::new(),
+            path: Regex::new("^/graphql$").unwrap(),
+            host: None,
+            ttl: None,
+            ttl_status: HashMap::new(),
+            bypass: false,
+            buckets: Vec::new(),
+            max_body_size: None,
+            post: false,
+            post_canonical_json: false,
+            post_max_body_size: 1024,
+        };
+
+        assert!(CacheCheck::is_cacheable_method(&Method::Get, None), "GET");
+        assert!(CacheCheck::is_cacheable_method(&Method::Head, None), "HEAD");
+        assert!(
+            CacheCheck::is_cacheable_method(&Method::Options, None),
+            "OPTIONS"
+        );
+        assert!(
+            !CacheCheck::is_cacheable_method(&Method::Post, None),
+            "POST"
+        );
+        assert!(
+            !CacheCheck::is_cacheable_method(&Method::Post, Some(&rule)),
+            "POST w/ rule"
+        );
+
+        rule.post = true;
+
+        assert!(
+            CacheCheck::is_cacheable_method(&Method::Post, Some(&rule)),
+            "POST w/ POST rule"
+        );
+        assert!(
+            !CacheCheck::is_cacheable_method(&Method::Put, Some(&rule)),
+            "PUT w/ POST rule"
+        );
+
+        let mut headers = Headers::new();
+
+        assert!(
+            !CacheCheck::from_request_body(&Method::Post, &rule, &headers),
+            "POST w/o length"
+        );
+
+        headers.set(ContentLength(1024));
+
+        assert!(
+            CacheCheck::from_request_body(&Method::Post, &rule, &headers),
+            "POST w/ length"
+        );
+
+        headers.set(ContentLength(1025));
+
+        assert!(
+            !CacheCheck::from_request_body(&Method::Post, &rule, &headers),
+            "POST w/ excessive length"
+        );
     }
 
     #[test]
@@ -94,4 +183,98 @@ mod tests {
             "500 Internal Server Error"
         );
     }
+
+    #[test]
+    fn it_asserts_valid_cacheable_policy() {
+        let mut rule = ConfigCacheRule {
+            methods: Vec::new(),
+            path: Regex::new("^/").unwrap(),
+            host: None,
+            ttl: None,
+            ttl_status: [("401".to_string(), 0), ("404".to_string(), 30)].into(),
+            bypass: false,
+            buckets: Vec::new(),
+            max_body_size: Some(1024),
+            post: false,
+            post_canonical_json: false,
+            post_max_body_size: 1024,
+        };
+
+        let ttl_status = |status| CacheTTL::from_rules(status, Some(&rule)).unwrap_or(600);
+
+        let (ttl_ok, ttl_not_found, ttl_unauthorized) = (
+            ttl_status(StatusCode::Ok),
+            ttl_status(StatusCode::NotFound),
+            ttl_status(StatusCode::Unauthorized),
+        );
+
+        assert!(CacheCheck::from_policy(None, 600, 4096), "none");
+        assert!(!CacheCheck::from_policy(None, 0, 4096), "zero ttl");
+        assert!(CacheCheck::from_policy(Some(&rule), ttl_ok, 1024), "ok");
+        assert!(
+            CacheCheck::from_policy(Some(&rule), ttl_not_found, 0),
+            "404"
+        );
+        assert!(
+            !CacheCheck::from_policy(Some(&rule), ttl_unauthorized, 0),
+            "401"
+        );
+        assert!(
+            !CacheCheck::from_policy(Some(&rule), ttl_ok, 1025),
+            "too large"
+        );
+
+        rule.bypass = true;
+
+        assert!(!CacheCheck::from_policy(Some(&rule), ttl_ok, 0), "bypass");
+    }
+
+    #[test]
+    fn it_asserts_valid_cacheable_response() {
+        let mut headers = Headers::new();
+
+        assert!(CacheCheck::is_cacheable_response(&headers), "none");
+
+        headers.set_raw("Vary", "Accept-Language");
+
+        assert!(CacheCheck::is_cacheable_response(&headers), "vary");
+
+        headers.set_raw("Vary", "*");
+
+        assert!(!CacheCheck::is_cacheable_response(&headers), "vary any");
+    }
+
+    #[test]
+    fn it_asserts_valid_cacheable_http_response() {
+        let mut headers = Headers::new();
+
+        assert!(CacheCheck::is_cacheable_http_response(&headers), "none");
+
+        headers.set_raw("Cache-Control", "public, max-age=60");
+
+        assert!(CacheCheck::is_cacheable_http_response(&headers), "public");
+
+        headers.set_raw("Cache-Control", "no-store");
+
+        assert!(
+            !CacheCheck::is_cacheable_http_response(&headers),
+            "no-store"
+        );
+
+        headers.set_raw("Cache-Control", "max-age=60, private");
+
+        assert!(!CacheCheck::is_cacheable_http_response(&headers), "private");
+
+        headers.set_raw("Cache-Control", "no-cache=\"Set-Cookie\"");
+
+        assert!(
+            !CacheCheck::is_cacheable_http_response(&headers),
+            "no-cache"
+        );
+
+        headers.set_raw("Cache-Control", "max-age=60");
+        headers.set_raw("Set-Cookie", "session=1");
+
+        assert!(!CacheCheck::is_cacheable_http_response(&headers), "cookie");
+    }
 }
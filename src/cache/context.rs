@@ -0,0 +1,23 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use hyper::{Headers, Method, Uri};
+
+use crate::config::config::ConfigCacheRule;
+
+// Notice: the context of a request is cloned for background tasks (eg. revalidations), \
+//   thus it must only hold what is needed to read or write its cache value.
+#[derive(Clone)]
+pub struct CacheContext {
+    pub shard: u8,
+    pub auth_hash: String,
+    pub key: String,
+    pub key_mask: String,
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: Headers,
+    pub rule: Option<&'static ConfigCacheRule>,
+}
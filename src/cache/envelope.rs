@@ -0,0 +1,200 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use httparse;
+
+pub struct CacheEnvelope {
+    pub status: u16,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CacheEnvelopeError {
+    Truncated,
+    Malformed,
+    UnsupportedVersion(u8),
+}
+
+// Envelope layout (all integers are big-endian):
+//   [magic: 2 bytes] [version: u8] [status: u16] [headers count: u16] \
+//   [[name length: u16] [name] [value length: u32] [value]]... [body: remaining bytes]
+// Notice: the magic cannot be mistaken for a legacy text entry, which starts with 'HTTP/'.
+const ENVELOPE_MAGIC: [u8; 2] = [0xb1, 0x0e];
+const ENVELOPE_VERSION: u8 = 1;
+
+const LEGACY_PREFIX: &[u8] = b"HTTP/";
+const LEGACY_PARSE_MAX_HEADERS: usize = 100;
+
+impl CacheEnvelope {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.body.len() + 64);
+
+        bytes.extend_from_slice(&ENVELOPE_MAGIC);
+        bytes.push(ENVELOPE_VERSION);
+        bytes.extend_from_slice(&self.status.to_be_bytes());
+        bytes.extend_from_slice(&(self.headers.len() as u16).to_be_bytes());
+
+        for (name, value) in &self.headers {
+            bytes.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(value);
+        }
+
+        bytes.extend_from_slice(&self.body);
+
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, CacheEnvelopeError> {
+        if let Some(envelope_bytes) = bytes.strip_prefix(&ENVELOPE_MAGIC) {
+            match envelope_bytes.split_first() {
+                Some((&ENVELOPE_VERSION, envelope_data)) => Self::decode_v1(envelope_data),
+                Some((&version, _)) => Err(CacheEnvelopeError::UnsupportedVersion(version)),
+                None => Err(CacheEnvelopeError::Truncated),
+            }
+        } else if bytes.starts_with(LEGACY_PREFIX) {
+            // Entries written before envelopes were introduced are HTTP text blobs, which \
+            //   are still served until they expire or get overwritten by an envelope.
+            Self::decode_legacy(bytes)
+        } else {
+            Err(CacheEnvelopeError::Malformed)
+        }
+    }
+
+    fn decode_v1(bytes: &[u8]) -> Result<Self, CacheEnvelopeError> {
+        let mut reader = CacheEnvelopeReader { bytes };
+
+        let status = u16::from_be_bytes(reader.take_array()?);
+        let headers_count = u16::from_be_bytes(reader.take_array()?);
+
+        let mut headers = Vec::with_capacity(headers_count as usize);
+
+        for _ in 0..headers_count {
+            let name_length = u16::from_be_bytes(reader.take_array()?) as usize;
+            let name = String::from_utf8(reader.take(name_length)?.to_vec())
+                .or(Err(CacheEnvelopeError::Malformed))?;
+
+            let value_length = u32::from_be_bytes(reader.take_array()?) as usize;
+            let value = reader.take(value_length)?.to_vec();
+
+            headers.push((name, value));
+        }
+
+        Ok(CacheEnvelope {
+            status,
+            headers,
+            body: reader.bytes.to_vec(),
+        })
+    }
+
+    fn decode_legacy(bytes: &[u8]) -> Result<Self, CacheEnvelopeError> {
+        let mut headers = [httparse::EMPTY_HEADER; LEGACY_PARSE_MAX_HEADERS];
+        let mut res = httparse::Response::new(&mut headers);
+
+        // Split headers from body, which starts right after the headers end
+        let body_offset = match res.parse(bytes) {
+            Ok(httparse::Status::Complete(body_offset)) => body_offset,
+            Ok(httparse::Status::Partial) => return Err(CacheEnvelopeError::Truncated),
+            Err(_) => return Err(CacheEnvelopeError::Malformed),
+        };
+
+        Ok(CacheEnvelope {
+            status: res.code.unwrap_or(500u16),
+            headers: res
+                .headers
+                .iter()
+                .map(|header| (header.name.to_string(), header.value.to_vec()))
+                .collect(),
+            body: bytes[body_offset..].to_vec(),
+        })
+    }
+}
+
+struct CacheEnvelopeReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> CacheEnvelopeReader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], CacheEnvelopeError> {
+        if self.bytes.len() < length {
+            return Err(CacheEnvelopeError::Truncated);
+        }
+
+        let (taken, rest) = self.bytes.split_at(length);
+
+        self.bytes = rest;
+
+        Ok(taken)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], CacheEnvelopeError> {
+        let mut array = [0; N];
+
+        array.copy_from_slice(self.take(N)?);
+
+        Ok(array)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_encodes_and_decodes_envelopes() {
+        let envelope = CacheEnvelope {
+            status: 200,
+            headers: vec![
+                ("Content-Type".to_string(), b"image/png".to_vec()),
+                ("Set-Cookie".to_string(), b"a=1".to_vec()),
+                ("Set-Cookie".to_string(), b"b=2".to_vec()),
+            ],
+            body: b"\x89PNG\r\n\x1a\n\x00\xff\n\n".to_vec(),
+        };
+
+        let decoded = CacheEnvelope::decode(&envelope.encode()).unwrap();
+
+        assert_eq!(decoded.status, envelope.status);
+        assert_eq!(decoded.headers, envelope.headers);
+        assert_eq!(decoded.body, envelope.body);
+    }
+
+    #[test]
+    fn it_decodes_legacy_entries() {
+        let decoded = CacheEnvelope::decode(
+            b"HTTP/1.1 404 Not Found\nContent-Type: text/plain\nServer: Kestrel\n\nnot\nfound",
+        )
+        .unwrap();
+
+        assert_eq!(decoded.status, 404);
+        assert_eq!(
+            decoded.headers,
+            vec![
+                ("Content-Type".to_string(), b"text/plain".to_vec()),
+                ("Server".to_string(), b"Kestrel".to_vec()),
+            ]
+        );
+        assert_eq!(decoded.body, b"not\nfound".to_vec());
+    }
+
+    #[test]
+    fn it_rejects_invalid_envelopes() {
+        assert_eq!(
+            CacheEnvelope::decode(&[0xb1, 0x0e, 9, 0, 200]).err(),
+            Some(CacheEnvelopeError::UnsupportedVersion(9))
+        );
+        assert_eq!(
+            CacheEnvelope::decode(&[0xb1, 0x0e, 1, 0, 200, 0, 1, 0]).err(),
+            Some(CacheEnvelopeError::Truncated)
+        );
+        assert_eq!(
+            CacheEnvelope::decode(b"{}").err(),
+            Some(CacheEnvelopeError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,186 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use futures::future::{self, Future};
+use hyper::Method;
+
+use super::route::CacheRoute;
+use super::store::{CachePurgeVariant, EXECUTOR_POOL};
+use crate::config::config::ConfigCacheInvalidate;
+use crate::APP_CACHE_STORE;
+use crate::APP_CONF;
+
+pub struct CacheInvalidate;
+
+pub type CacheInvalidateFuture = Box<dyn Future<Item = (), Error = ()>>;
+
+impl CacheInvalidate {
+    pub fn from_request(
+        shard: u8,
+        auth_hash: &str,
+        method: &Method,
+        path: &str,
+    ) -> CacheInvalidateFuture {
+        let purge_paths = Self::gen_purge_paths(&APP_CONF.cache.invalidate, method, path);
+
+        if purge_paths.is_empty() {
+            return Box::new(future::ok(()));
+        }
+
+        let route_keys = purge_paths
+            .iter()
+            .map(|purge_path| {
+                CacheRoute::gen_key_route_from_hash(
+                    shard,
+                    &CacheRoute::gen_route_hash(auth_hash, purge_path),
+                )
+                .0
+            })
+            .collect::<Vec<String>>();
+
+        debug!(
+            "invalidating routes: {:?} after {} on {}",
+            purge_paths, method, path
+        );
+
+        Self::purge(CachePurgeVariant::Route, shard, route_keys)
+    }
+
+    pub fn from_buckets(shard: u8, buckets: &[String]) -> CacheInvalidateFuture {
+        // Buckets are hashed the same way as when tagging responses with them
+        let bucket_keys = buckets
+            .iter()
+            .map(|bucket| CacheRoute::gen_key_bucket_from_hash(shard, &CacheRoute::hash(bucket)).0)
+            .collect::<Vec<String>>();
+
+        debug!("invalidating buckets: {:?}", buckets);
+
+        Self::purge(CachePurgeVariant::Bucket, shard, bucket_keys)
+    }
+
+    pub fn from_entry(
+        shard: u8,
+        auth_hash: &str,
+        path: &str,
+        query: Option<&str>,
+    ) -> CacheInvalidateFuture {
+        // Purge the route for all methods that read it, as cached entries are keyed per method
+        let entry_keys = [Method::Get, Method::Head]
+            .iter()
+            .map(|method| CacheRoute::gen_key_entry(shard, auth_hash, method, path, query).0)
+            .collect::<Vec<String>>();
+
+        debug!("invalidating entries: {:?}", entry_keys);
+
+        Self::purge(CachePurgeVariant::Key, shard, entry_keys)
+    }
+
+    pub fn has_rules() -> bool {
+        !APP_CONF.cache.invalidate.is_empty()
+    }
+
+    fn purge(
+        variant: CachePurgeVariant,
+        shard: u8,
+        key_tags: Vec<String>,
+    ) -> CacheInvalidateFuture {
+        Box::new(EXECUTOR_POOL.spawn_fn(move || {
+            let mut has_failed = false;
+
+            // Notice: keep purging remaining tags on failure, and only report it at the end
+            for key_tag in key_tags {
+                if let Err(err) = APP_CACHE_STORE.purge_tag(&variant, shard, &key_tag) {
+                    warn!(
+                        "could not invalidate {:?} for tag: {} because: {:?}",
+                        variant, key_tag, err
+                    );
+
+                    has_failed = true;
+                }
+            }
+
+            if has_failed {
+                Err(())
+            } else {
+                Ok(())
+            }
+        }))
+    }
+
+    fn gen_purge_paths(
+        rules: &[ConfigCacheInvalidate],
+        method: &Method,
+        path: &str,
+    ) -> Vec<String> {
+        let mut purge_paths = Vec::new();
+
+        // All matching rules apply, each purging the request path if it lists no path
+        for rule in rules {
+            let is_method_match = rule.methods.is_empty()
+                || rule
+                    .methods
+                    .iter()
+                    .any(|rule_method| rule_method.eq_ignore_ascii_case(method.as_ref()));
+
+            if let Some(captures) = rule.path.captures(path).filter(|_| is_method_match) {
+                if rule.purge.is_empty() {
+                    purge_paths.push(path.to_string());
+                }
+
+                // Purge paths may refer to groups captured from the request path (eg. '$1')
+                for purge in &rule.purge {
+                    let mut purge_path = String::new();
+
+                    captures.expand(purge, &mut purge_path);
+
+                    purge_paths.push(purge_path);
+                }
+            }
+        }
+
+        purge_paths.sort();
+        purge_paths.dedup();
+
+        purge_paths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    #[test]
+    fn it_generates_purge_paths() {
+        let rules = vec![
+            ConfigCacheInvalidate {
+                methods: vec!["PUT".to_string(), "delete".to_string()],
+                path: Regex::new("^/users/([0-9]+)$").unwrap(),
+                purge: vec!["/users/$1".to_string(), "/users".to_string()],
+            },
+            ConfigCacheInvalidate {
+                methods: Vec::new(),
+                path: Regex::new("^/posts/").unwrap(),
+                purge: Vec::new(),
+            },
+        ];
+
+        assert_eq!(
+            CacheInvalidate::gen_purge_paths(&rules, &Method::Put, "/users/42"),
+            vec!["/users".to_string(), "/users/42".to_string()]
+        );
+        assert_eq!(
+            CacheInvalidate::gen_purge_paths(&rules, &Method::Delete, "/users/42"),
+            vec!["/users".to_string(), "/users/42".to_string()]
+        );
+        assert!(CacheInvalidate::gen_purge_paths(&rules, &Method::Patch, "/users/42").is_empty());
+        assert!(CacheInvalidate::gen_purge_paths(&rules, &Method::Put, "/users/me").is_empty());
+        assert_eq!(
+            CacheInvalidate::gen_purge_paths(&rules, &Method::Post, "/posts/1"),
+            vec!["/posts/1".to_string()]
+        );
+    }
+}
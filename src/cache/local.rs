@@ -0,0 +1,292 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::lru::CacheLru;
+use super::store::{CacheStoreDeadlines, CacheStoreMeta};
+
+pub struct CacheLocal {
+    ttl: usize,
+    state: Mutex<CacheLocalState>,
+}
+
+struct CacheLocalState {
+    entries: CacheLru<CacheLocalEntry>,
+    tags: HashMap<String, HashSet<String>>,
+}
+
+struct CacheLocalEntry {
+    fingerprint: String,
+    tags: Vec<String>,
+    body: Option<Vec<u8>>,
+    fresh_until: u64,
+    stale_until: u64,
+    expires_at: Instant,
+}
+
+impl CacheLocal {
+    pub fn new(max_size: usize, ttl: usize) -> Self {
+        Self {
+            ttl,
+            state: Mutex::new(CacheLocalState {
+                entries: CacheLru::new(max_size),
+                tags: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn get_meta(&self, key: &str) -> Option<CacheStoreMeta> {
+        self.state
+            .lock()
+            .ok()?
+            .acquire_live(key)
+            .map(|entry| CacheStoreMeta {
+                fingerprint: entry.fingerprint.to_owned(),
+                fresh_until: entry.fresh_until,
+                stale_until: entry.stale_until,
+                vary: Vec::new(),
+            })
+    }
+
+    pub fn get_body(&self, key: &str) -> Option<Vec<u8>> {
+        self.state
+            .lock()
+            .ok()?
+            .acquire_live(key)
+            .and_then(|entry| entry.body.to_owned())
+    }
+
+    pub fn set_meta(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        tags: &[String],
+        fresh_until: u64,
+        stale_until: u64,
+    ) {
+        self.insert(
+            key,
+            fingerprint,
+            tags,
+            None,
+            &CacheStoreDeadlines {
+                fresh_until,
+                stale_until,
+                expire_after: self.ttl,
+            },
+        );
+    }
+
+    pub fn set_body(&self, key: &str, body: &[u8]) {
+        if let Ok(mut state) = self.state.lock() {
+            // Only attach body to a known meta, as the body fingerprint must be known
+            let entry = match state.entries.remove(key) {
+                Some(entry) => entry,
+                None => return,
+            };
+
+            let entry_size = Self::size_of(key, &entry.fingerprint, &entry.tags, Some(body));
+
+            state.store(
+                key,
+                CacheLocalEntry {
+                    body: Some(body.to_vec()),
+                    ..entry
+                },
+                entry_size,
+            );
+        }
+    }
+
+    pub fn set(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        tags: &[String],
+        body: &[u8],
+        deadlines: &CacheStoreDeadlines,
+    ) {
+        self.insert(
+            key,
+            fingerprint,
+            tags,
+            Some(body),
+            &CacheStoreDeadlines {
+                expire_after: cmp::min(deadlines.expire_after, self.ttl),
+                ..*deadlines
+            },
+        );
+    }
+
+    pub fn invalidate_tag(&self, tag: &str) {
+        if let Ok(mut state) = self.state.lock() {
+            if let Some(tag_keys) = state.tags.remove(tag) {
+                debug!(
+                    "invalidating {} local keys for tag: {}",
+                    tag_keys.len(),
+                    tag
+                );
+
+                for tag_key in tag_keys {
+                    state.unlink(&tag_key);
+                }
+            }
+        }
+    }
+
+    pub fn invalidate_all(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.entries.clear();
+            state.tags.clear();
+        }
+    }
+
+    fn insert(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        tags: &[String],
+        body: Option<&[u8]>,
+        deadlines: &CacheStoreDeadlines,
+    ) {
+        if let Ok(mut state) = self.state.lock() {
+            let entry_size = Self::size_of(key, fingerprint, tags, body);
+
+            state.unlink(key);
+
+            state.store(
+                key,
+                CacheLocalEntry {
+                    fingerprint: fingerprint.to_string(),
+                    tags: tags.to_vec(),
+                    body: body.map(<[u8]>::to_vec),
+                    fresh_until: deadlines.fresh_until,
+                    stale_until: deadlines.stale_until,
+                    expires_at: Instant::now() + Duration::from_secs(deadlines.expire_after as u64),
+                },
+                entry_size,
+            );
+        }
+    }
+
+    fn size_of(key: &str, fingerprint: &str, tags: &[String], body: Option<&[u8]>) -> usize {
+        key.len()
+            + fingerprint.len()
+            + tags.iter().map(String::len).sum::<usize>()
+            + body.map_or(0, <[u8]>::len)
+    }
+}
+
+impl CacheLocalState {
+    fn acquire_live(&mut self, key: &str) -> Option<&CacheLocalEntry> {
+        let is_live = match self.entries.peek(key) {
+            Some(entry) => Instant::now() < entry.expires_at,
+            None => return None,
+        };
+
+        if is_live {
+            self.entries.get(key)
+        } else {
+            self.unlink(key);
+
+            None
+        }
+    }
+
+    fn store(&mut self, key: &str, entry: CacheLocalEntry, entry_size: usize) {
+        let tags = entry.tags.to_owned();
+
+        for (evicted_key, evicted_entry) in self.entries.insert(key.to_string(), entry, entry_size)
+        {
+            Self::unindex(&mut self.tags, &evicted_key, &evicted_entry);
+
+            // Value cannot fit in local cache, do not index it
+            if evicted_key == key {
+                return;
+            }
+        }
+
+        for tag in tags {
+            self.tags.entry(tag).or_default().insert(key.to_string());
+        }
+    }
+
+    fn unlink(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            Self::unindex(&mut self.tags, key, &entry);
+        }
+    }
+
+    fn unindex(tags: &mut HashMap<String, HashSet<String>>, key: &str, entry: &CacheLocalEntry) {
+        for tag in &entry.tags {
+            if let Some(tag_keys) = tags.get_mut(tag) {
+                tag_keys.remove(key);
+
+                if tag_keys.is_empty() {
+                    tags.remove(tag);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_serves_and_invalidates_local_values() {
+        let local = CacheLocal::new(10_000, 10);
+        let tags = vec![
+            "bloom:0:b:2eb6c00c".to_string(),
+            "bloom:0:a:dc56d17a".to_string(),
+        ];
+
+        local.set_meta("bloom:0:c:dc56d17a:7cf7a048", "f773d6f1", &tags, 0, 0);
+
+        assert_eq!(
+            local
+                .get_meta("bloom:0:c:dc56d17a:7cf7a048")
+                .map(|meta| meta.fingerprint),
+            Some("f773d6f1".to_string())
+        );
+        assert_eq!(local.get_body("bloom:0:c:dc56d17a:7cf7a048"), None);
+
+        local.set_body("bloom:0:c:dc56d17a:7cf7a048", b"{}");
+
+        assert_eq!(
+            local.get_body("bloom:0:c:dc56d17a:7cf7a048"),
+            Some(b"{}".to_vec())
+        );
+
+        local.invalidate_tag("bloom:0:b:2eb6c00c");
+
+        assert!(local.get_meta("bloom:0:c:dc56d17a:7cf7a048").is_none());
+    }
+
+    #[test]
+    fn it_expires_local_values() {
+        let local = CacheLocal::new(10_000, 10);
+
+        local.set(
+            "bloom:0:c:dc56d17a:7cf7a048",
+            "f773d6f1",
+            &[],
+            b"{}",
+            &CacheStoreDeadlines {
+                fresh_until: 0,
+                stale_until: 0,
+                expire_after: 0,
+            },
+        );
+
+        assert!(local.get_meta("bloom:0:c:dc56d17a:7cf7a048").is_none());
+    }
+}
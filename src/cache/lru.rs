@@ -0,0 +1,182 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use std::collections::{BTreeMap, HashMap};
+
+pub struct CacheLru<V> {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    entries: HashMap<String, CacheLruEntry<V>>,
+    order: BTreeMap<u64, String>,
+}
+
+struct CacheLruEntry<V> {
+    value: V,
+    size: usize,
+    tick: u64,
+}
+
+impl<V> CacheLru<V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<&V> {
+        let tick = self.next_tick();
+
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                // Bump entry to the most recently used position
+                if let Some(order_key) = self.order.remove(&entry.tick) {
+                    self.order.insert(tick, order_key);
+                }
+
+                entry.tick = tick;
+
+                Some(&entry.value)
+            }
+            None => None,
+        }
+    }
+
+    pub fn peek(&self, key: &str) -> Option<&V> {
+        self.entries.get(key).map(|entry| &entry.value)
+    }
+
+    pub fn peek_mut(&mut self, key: &str) -> Option<&mut V> {
+        self.entries.get_mut(key).map(|entry| &mut entry.value)
+    }
+
+    pub fn insert(&mut self, key: String, value: V, size: usize) -> Vec<(String, V)> {
+        let mut evicted = Vec::new();
+
+        // Replace any previous value for this key (this is not an eviction)
+        self.remove(&key);
+
+        // Value can never fit in this LRU? Bounce it back right away
+        if size > self.capacity {
+            evicted.push((key, value));
+
+            return evicted;
+        }
+
+        // Evict least recently used entries until value fits
+        while self.size + size > self.capacity {
+            match self.pop_oldest() {
+                Some(oldest) => evicted.push(oldest),
+                None => break,
+            }
+        }
+
+        let tick = self.next_tick();
+
+        self.order.insert(tick, key.clone());
+        self.entries
+            .insert(key, CacheLruEntry { value, size, tick });
+
+        self.size += size;
+
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        self.entries.remove(key).map(|entry| {
+            self.order.remove(&entry.tick);
+
+            self.size -= entry.size;
+
+            entry.value
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+
+        self.size = 0;
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn pop_oldest(&mut self) -> Option<(String, V)> {
+        let oldest_tick = *self.order.keys().next()?;
+        let oldest_key = self.order.remove(&oldest_tick)?;
+
+        self.entries.remove(&oldest_key).map(|entry| {
+            self.size -= entry.size;
+
+            (oldest_key, entry.value)
+        })
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_evicts_least_recently_used() {
+        let mut lru = CacheLru::new(10);
+
+        assert!(lru.insert("a".to_string(), 1, 4).is_empty());
+        assert!(lru.insert("b".to_string(), 2, 4).is_empty());
+
+        // Bump 'a', so that 'b' becomes the least recently used entry
+        assert_eq!(lru.get("a"), Some(&1));
+
+        let evicted = lru.insert("c".to_string(), 3, 4);
+
+        assert_eq!(evicted, vec![("b".to_string(), 2)]);
+        assert_eq!(lru.peek("a"), Some(&1));
+        assert_eq!(lru.peek("b"), None);
+        assert_eq!(lru.peek("c"), Some(&3));
+        assert_eq!(lru.size(), 8);
+    }
+
+    #[test]
+    fn it_replaces_and_removes_entries() {
+        let mut lru = CacheLru::new(10);
+
+        lru.insert("a".to_string(), 1, 4);
+        lru.insert("a".to_string(), 2, 6);
+
+        assert_eq!(lru.peek("a"), Some(&2));
+        assert_eq!(lru.size(), 6);
+
+        assert_eq!(lru.remove("a"), Some(2));
+        assert_eq!(lru.remove("a"), None);
+        assert_eq!(lru.size(), 0);
+    }
+
+    #[test]
+    fn it_rejects_oversized_entries() {
+        let mut lru = CacheLru::new(10);
+
+        lru.insert("a".to_string(), 1, 4);
+
+        assert_eq!(
+            lru.insert("b".to_string(), 2, 11),
+            vec![("b".to_string(), 2)]
+        );
+        assert_eq!(lru.peek("a"), Some(&1));
+        assert_eq!(lru.peek("b"), None);
+    }
+}
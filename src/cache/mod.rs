@@ -7,9 +7,16 @@
 #[macro_use]
 mod macros;
 
-mod check;
+mod backend;
+mod local;
+mod lru;
 
+pub mod check;
+pub mod context;
+pub mod envelope;
+pub mod invalidate;
 pub mod read;
 pub mod route;
 pub mod store;
+pub mod ttl;
 pub mod write;
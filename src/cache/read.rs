@@ -5,9 +5,12 @@
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
 use futures::future::{self, Future};
-use hyper::Method;
+use hyper::{Headers, Method};
 
 use super::check::CacheCheck;
+use super::route::CacheRoute;
+use super::store::{CacheStoreError, CacheStoreMeta};
+use crate::config::config::ConfigCacheRule;
 
 use crate::APP_CACHE_STORE;
 use crate::APP_CONF;
@@ -20,20 +23,54 @@ pub enum CacheReadError {
     StoreFailure,
 }
 
-type CacheReadResult = Result<String, CacheReadError>;
+type CacheReadResult = Result<(String, CacheStoreMeta), CacheReadError>;
 type CacheReadResultFuture = Box<dyn Future<Item = CacheReadResult, Error = ()>>;
+type CacheReadVariantFuture =
+    Box<dyn Future<Item = Option<(String, CacheStoreMeta)>, Error = CacheStoreError>>;
 
-type CacheReadOptionalResult = Result<Option<String>, CacheReadError>;
+type CacheReadOptionalResult = Result<Option<Vec<u8>>, CacheReadError>;
 type CacheReadOptionalResultFuture = Box<dyn Future<Item = CacheReadOptionalResult, Error = ()>>;
 
 impl CacheRead {
-    pub fn acquire_meta(shard: u8, key: &str, method: &Method) -> CacheReadResultFuture {
-        if !APP_CONF.cache.disable_read && CacheCheck::from_request(method) {
+    pub fn acquire_meta(
+        shard: u8,
+        key: &str,
+        method: &Method,
+        headers: &Headers,
+        rule: Option<&ConfigCacheRule>,
+    ) -> CacheReadResultFuture {
+        if !APP_CONF.cache.disable_read && CacheCheck::from_request(method, rule) {
             debug!("key: {} cacheable, reading cache", &key);
 
+            let (key, headers) = (key.to_string(), headers.to_owned());
+
             Box::new(
                 APP_CACHE_STORE
-                    .get_meta(shard, key.to_string())
+                    .get_meta(shard, key.to_owned())
+                    .and_then(move |acquired| -> CacheReadVariantFuture {
+                        match acquired {
+                            Some(meta) if !meta.vary.is_empty() => {
+                                // Route varies on request headers, read value for this variant
+                                let variant_key = CacheRoute::gen_key_variant_from_hash(
+                                    &key,
+                                    &CacheRoute::gen_variant_hash(&meta.vary, &headers),
+                                );
+
+                                debug!("key: {} varies, reading variant: {}", key, variant_key);
+
+                                Box::new(
+                                    APP_CACHE_STORE.get_meta(shard, variant_key.to_owned()).map(
+                                        move |acquired| {
+                                            acquired
+                                                .filter(|meta| meta.vary.is_empty())
+                                                .map(|meta| (variant_key, meta))
+                                        },
+                                    ),
+                                )
+                            }
+                            _ => Box::new(future::ok(acquired.map(|meta| (key, meta)))),
+                        }
+                    })
                     .and_then(|acquired| {
                         acquired.map_or_else(
                             || {
@@ -87,11 +124,15 @@ mod tests {
     #[test]
     #[should_panic]
     fn it_fails_acquiring_cache_meta() {
-        assert!(
-            CacheRead::acquire_meta(0, "bloom:0:c:90d52bc6:f773d6f1", &Method::Get)
-                .poll()
-                .is_err()
-        );
+        assert!(CacheRead::acquire_meta(
+            0,
+            "bloom:0:c:90d52bc6:f773d6f1",
+            &Method::Get,
+            &Headers::new(),
+            None
+        )
+        .poll()
+        .is_err());
     }
 
     #[test]
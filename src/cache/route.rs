@@ -5,11 +5,25 @@
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
 use farmhash;
-use hyper::header::Origin;
-use hyper::{HttpVersion, Method};
+use hyper::header::{Cookie, Host, Origin};
+use hyper::{Headers, HttpVersion, Method};
+use serde_json::Value;
+
+use crate::config::config::{ConfigCacheKey, ConfigCacheRule};
+use crate::APP_CONF;
 
 pub struct CacheRoute;
 
+#[derive(Clone, Copy)]
+pub struct CacheRouteRequest<'a> {
+    pub version: HttpVersion,
+    pub method: &'a Method,
+    pub path: &'a str,
+    pub query: Option<&'a str>,
+    pub headers: &'a Headers,
+    pub body_hash: Option<&'a str>,
+}
+
 pub const ROUTE_HASH_SIZE: usize = 16;
 
 pub static ROUTE_PREFIX: &str = "bloom";
@@ -37,23 +51,215 @@ impl CacheRoute {
         (format!("{ROUTE_PREFIX}:{shard}:{mask}"), mask)
     }
 
-    pub fn gen_key_cache(
+    pub fn gen_bucket_hierarchy(buckets: Vec<String>, separator: &str) -> Vec<String> {
+        if separator.is_empty() {
+            return buckets;
+        }
+
+        let mut hierarchy = Vec::new();
+
+        // Each ancestor of a bucket gets a wildcard bucket, purging all its descendants \
+        //   (eg. 'user:42:posts' is also part of the 'user:*' and 'user:42:*' buckets)
+        for bucket in buckets {
+            let mut ancestor_end = 0;
+
+            while let Some(offset) = bucket[ancestor_end..].find(separator) {
+                ancestor_end += offset + separator.len();
+
+                hierarchy.push(format!("{}*", &bucket[..ancestor_end]));
+            }
+
+            hierarchy.push(bucket);
+        }
+
+        hierarchy.sort();
+        hierarchy.dedup();
+
+        hierarchy
+    }
+
+    pub fn gen_key_route_from_hash(shard: u8, route_hash: &str) -> (String, String) {
+        let mask = format!("r:{route_hash}");
+
+        (format!("{ROUTE_PREFIX}:{shard}:{mask}"), mask)
+    }
+
+    pub fn gen_route_hash(auth_hash: &str, path: &str) -> String {
+        // Notice: the path is normalized as it is in cache keys, so that invalidations match \
+        //   routes cached from all of their path variants (eg. case-folded paths).
+        let path = Self::gen_key_path(path, Self::match_key_rule(path));
+
+        Self::hash(&format!("[{auth_hash}|{path}]"))
+    }
+
+    pub fn gen_key_path(path: &str, key_rule: Option<&ConfigCacheKey>) -> String {
+        if key_rule.is_some_and(|key_rule| key_rule.path_case_fold) {
+            path.to_lowercase()
+        } else {
+            path.to_string()
+        }
+    }
+
+    pub fn gen_key_entry(
         shard: u8,
         auth_hash: &str,
-        version: HttpVersion,
         method: &Method,
         path: &str,
         query: Option<&str>,
-        origin: Option<&Origin>,
     ) -> (String, String) {
-        let bucket_raw = format!(
-            "[{}|{}|{}|{}|{}]",
+        // Notice: the entry key is the cache key of the route without its request variant \
+        //   (ie. protocol version, origin, headers and body), so that all cached variants of \
+        //   a route can be purged at once from its method, path and query only.
+        let (_, cache_mask) = Self::gen_key_cache(
+            shard,
+            auth_hash,
+            &CacheRouteRequest {
+                version: HttpVersion::Http11,
+                method,
+                path,
+                query,
+                headers: &Headers::new(),
+                body_hash: None,
+            },
+            Self::match_key_rule(path),
+        );
+
+        let mask = format!("k:{cache_mask}");
+
+        (format!("{ROUTE_PREFIX}:{shard}:{mask}"), mask)
+    }
+
+    pub fn gen_key_variant_from_hash(key: &str, variant_hash: &str) -> String {
+        format!("{key}:{variant_hash}")
+    }
+
+    pub fn gen_variant_hash(vary: &[String], headers: &Headers) -> String {
+        let variant_raw = vary
+            .iter()
+            .map(|header_name| {
+                let header_value = headers.get_raw(header_name).map_or(String::new(), |raw| {
+                    raw.iter()
+                        .map(|line| String::from_utf8_lossy(line).into_owned())
+                        .collect::<Vec<String>>()
+                        .join(",")
+                });
+
+                format!("{header_name}={header_value}")
+            })
+            .collect::<Vec<String>>()
+            .join("|");
+
+        Self::hash(&format!("[{variant_raw}]"))
+    }
+
+    pub fn gen_key_lock_from_mask(shard: u8, cache_mask: &str) -> String {
+        format!("{ROUTE_PREFIX}:{shard}:l:{cache_mask}")
+    }
+
+    pub fn match_key_rule(path: &str) -> Option<&'static ConfigCacheKey> {
+        // First matching rule wins, in configuration order
+        APP_CONF
+            .cache
+            .key
+            .iter()
+            .find(|key_rule| key_rule.path.is_match(path))
+    }
+
+    pub fn match_rule(
+        method: &Method,
+        path: &str,
+        headers: &Headers,
+    ) -> Option<&'static ConfigCacheRule> {
+        let host = headers.get::<Host>().map(Host::hostname);
+
+        // First matching rule wins, in configuration order
+        APP_CONF
+            .cache
+            .rule
+            .iter()
+            .find(|rule| Self::is_rule_match(rule, method, path, host))
+    }
+
+    pub fn gen_key_cache(
+        shard: u8,
+        auth_hash: &str,
+        request: &CacheRouteRequest,
+        key_rule: Option<&ConfigCacheKey>,
+    ) -> (String, String) {
+        let CacheRouteRequest {
             version,
             method,
             path,
-            query.unwrap_or(""),
-            origin.unwrap_or(&Origin::null()),
-        );
+            query,
+            headers,
+            body_hash,
+        } = *request;
+
+        let origin = headers
+            .get::<Origin>()
+            .map_or_else(Origin::null, Clone::clone);
+
+        // Notice: without a key rule, the bucket must be kept as-is, as changing it would \
+        //   change all cache keys (which would make Bloom start with an empty cache).
+        let mut bucket_raw = match key_rule {
+            Some(key_rule) => format!(
+                "[{}|{}|{}|{}|{}",
+                if key_rule.ignore_version {
+                    String::new()
+                } else {
+                    version.to_string()
+                },
+                method,
+                Self::gen_key_path(path, Some(key_rule)),
+                Self::gen_query(query.unwrap_or(""), key_rule),
+                if key_rule.ignore_origin {
+                    String::new()
+                } else {
+                    origin.to_string()
+                },
+            ),
+            None => format!(
+                "[{}|{}|{}|{}|{}",
+                version,
+                method,
+                path,
+                query.unwrap_or(""),
+                origin
+            ),
+        };
+
+        if let Some(key_rule) = key_rule {
+            for header_name in &key_rule.headers {
+                let header_value = headers.get_raw(header_name).map_or(String::new(), |raw| {
+                    raw.iter()
+                        .map(|line| String::from_utf8_lossy(line).into_owned())
+                        .collect::<Vec<String>>()
+                        .join(",")
+                });
+
+                bucket_raw.push_str(&format!(
+                    "|h:{}={}",
+                    header_name.to_lowercase(),
+                    header_value
+                ));
+            }
+
+            for cookie_name in &key_rule.cookies {
+                let cookie_value = headers
+                    .get::<Cookie>()
+                    .and_then(|cookie| cookie.get(cookie_name))
+                    .unwrap_or("");
+
+                bucket_raw.push_str(&format!("|c:{cookie_name}={cookie_value}"));
+            }
+        }
+
+        // Request body is part of the key? (eg. cacheable POST requests)
+        if let Some(body_hash) = body_hash {
+            bucket_raw.push_str(&format!("|b:{body_hash}"));
+        }
+
+        bucket_raw.push(']');
 
         let route_hash = Self::hash(&bucket_raw);
 
@@ -62,6 +268,69 @@ impl CacheRoute {
         Self::gen_key_cache_from_hash(shard, auth_hash, &route_hash)
     }
 
+    pub fn gen_body_hash(body: &[u8], canonical_json: bool) -> String {
+        // Canonical JSON bodies differing only in formatting or key order share the same hash
+        // Notice: JSON objects get their keys sorted upon serialization, as they are \
+        //   deserialized to ordered maps.
+        let body_canonical = if canonical_json {
+            serde_json::from_slice::<Value>(body)
+                .ok()
+                .and_then(|value| serde_json::to_vec(&value).ok())
+        } else {
+            None
+        };
+
+        format!(
+            "{:x}",
+            farmhash::fingerprint64(body_canonical.as_deref().unwrap_or(body))
+        )
+    }
+
+    fn is_rule_match(
+        rule: &ConfigCacheRule,
+        method: &Method,
+        path: &str,
+        host: Option<&str>,
+    ) -> bool {
+        (rule.methods.is_empty()
+            || rule
+                .methods
+                .iter()
+                .any(|rule_method| rule_method.eq_ignore_ascii_case(method.as_ref())))
+            && rule.host.as_ref().is_none_or(|rule_host| {
+                host.is_some_and(|host| rule_host.eq_ignore_ascii_case(host))
+            })
+            && rule.path.is_match(path)
+    }
+
+    fn gen_query(query: &str, key_rule: &ConfigCacheKey) -> String {
+        let mut parameters = query
+            .split('&')
+            .filter(|parameter| {
+                let name = parameter.split('=').next().unwrap_or("");
+
+                !name.is_empty()
+                    && (key_rule.query_include.is_empty()
+                        || Self::match_query_names(&key_rule.query_include, name))
+                    && !Self::match_query_names(&key_rule.query_exclude, name)
+            })
+            .collect::<Vec<&str>>();
+
+        if key_rule.query_sort {
+            parameters.sort_unstable();
+        }
+
+        parameters.join("&")
+    }
+
+    fn match_query_names(names: &[String], name: &str) -> bool {
+        // Names ending with a wildcard match on prefix (eg. 'utm_*')
+        names.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        })
+    }
+
     pub fn hash(value: &str) -> String {
         debug!("hashing value: {}", value);
 
@@ -72,17 +341,27 @@ impl CacheRoute {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use regex::Regex;
+    use std::collections::HashMap;
 
     #[test]
     fn it_generates_valid_ns() {
+        let mut headers_origin = Headers::new();
+
+        headers_origin.set(Origin::new("https", "valeriansaliou.name", None));
+
         assert_eq!(
             CacheRoute::gen_key_cache(
                 0,
                 "dc56d17a",
-                HttpVersion::Http11,
-                &Method::Get,
-                "/",
-                Some(""),
+                &CacheRouteRequest {
+                    version: HttpVersion::Http11,
+                    method: &Method::Get,
+                    path: "/",
+                    query: Some(""),
+                    headers: &Headers::new(),
+                    body_hash: None,
+                },
                 None,
             ),
             (
@@ -95,10 +374,14 @@ mod tests {
             CacheRoute::gen_key_cache(
                 0,
                 "dc56d17a",
-                HttpVersion::Http11,
-                &Method::Post,
-                "/login",
-                Some(""),
+                &CacheRouteRequest {
+                    version: HttpVersion::Http11,
+                    method: &Method::Post,
+                    path: "/login",
+                    query: Some(""),
+                    headers: &Headers::new(),
+                    body_hash: None,
+                },
                 None,
             ),
             (
@@ -111,10 +394,14 @@ mod tests {
             CacheRoute::gen_key_cache(
                 7,
                 "6d0f1448",
-                HttpVersion::Http11,
-                &Method::Options,
-                "/feed",
-                Some(""),
+                &CacheRouteRequest {
+                    version: HttpVersion::Http11,
+                    method: &Method::Options,
+                    path: "/feed",
+                    query: Some(""),
+                    headers: &Headers::new(),
+                    body_hash: None,
+                },
                 None,
             ),
             (
@@ -127,11 +414,15 @@ mod tests {
             CacheRoute::gen_key_cache(
                 80,
                 "d73f0f31",
-                HttpVersion::H2,
-                &Method::Head,
-                "/user",
-                Some("u=1"),
-                Some(&Origin::new("https", "valeriansaliou.name", None)),
+                &CacheRouteRequest {
+                    version: HttpVersion::H2,
+                    method: &Method::Head,
+                    path: "/user",
+                    query: Some("u=1"),
+                    headers: &headers_origin,
+                    body_hash: None,
+                },
+                None,
             ),
             (
                 "bloom:80:c:d73f0f31:e258b8e6d24173f5".to_string(),
@@ -145,4 +436,267 @@ mod tests {
             "Route size should be 16 (dynamic)"
         );
     }
+
+    #[test]
+    fn it_generates_valid_ns_with_key_rule() {
+        let key_rule = ConfigCacheKey {
+            path: Regex::new("^/search").unwrap(),
+            query_include: Vec::new(),
+            query_exclude: vec!["utm_*".to_string(), "_".to_string()],
+            query_sort: true,
+            path_case_fold: true,
+            ignore_version: true,
+            ignore_origin: true,
+            headers: vec!["X-Device".to_string()],
+            cookies: vec!["currency".to_string()],
+        };
+
+        let gen_key = |version, path, query, headers: &Headers| {
+            CacheRoute::gen_key_cache(
+                0,
+                "dc56d17a",
+                &CacheRouteRequest {
+                    version,
+                    method: &Method::Get,
+                    path,
+                    query: Some(query),
+                    headers,
+                    body_hash: None,
+                },
+                Some(&key_rule),
+            )
+        };
+
+        let (mut headers, mut headers_other) = (Headers::new(), Headers::new());
+
+        headers.set_raw("X-Device", "mobile");
+        headers.set_raw("Cookie", "currency=EUR; session=1");
+        headers_other.set_raw("X-Device", "mobile");
+        headers_other.set_raw("Cookie", "session=2; currency=EUR");
+        headers_other.set(Origin::new("https", "valeriansaliou.name", None));
+
+        assert_eq!(
+            gen_key(HttpVersion::Http11, "/search", "a=1&b=2", &headers),
+            gen_key(
+                HttpVersion::H2,
+                "/Search",
+                "b=2&utm_source=x&a=1&_=1234",
+                &headers_other
+            )
+        );
+
+        headers_other.set_raw("X-Device", "desktop");
+
+        assert_ne!(
+            gen_key(HttpVersion::Http11, "/search", "a=1&b=2", &headers),
+            gen_key(HttpVersion::Http11, "/search", "a=1&b=2", &headers_other)
+        );
+    }
+
+    #[test]
+    fn it_generates_valid_body_hash() {
+        assert_eq!(
+            CacheRoute::gen_body_hash(br#"{"query":"{ user }","variables":{"id":1}}"#, true),
+            CacheRoute::gen_body_hash(
+                br#"{ "variables": { "id": 1 }, "query": "{ user }" }"#,
+                true
+            )
+        );
+        assert_ne!(
+            CacheRoute::gen_body_hash(br#"{"query":"{ user }","variables":{"id":1}}"#, false),
+            CacheRoute::gen_body_hash(
+                br#"{ "variables": { "id": 1 }, "query": "{ user }" }"#,
+                false
+            )
+        );
+        assert_eq!(
+            CacheRoute::gen_body_hash(b"q=search", true),
+            CacheRoute::gen_body_hash(b"q=search", false)
+        );
+    }
+
+    #[test]
+    fn it_filters_query_with_key_rule() {
+        let key_rule = ConfigCacheKey {
+            path: Regex::new("^/").unwrap(),
+            query_include: vec!["page".to_string(), "filter_*".to_string()],
+            query_exclude: Vec::new(),
+            query_sort: false,
+            path_case_fold: false,
+            ignore_version: false,
+            ignore_origin: false,
+            headers: Vec::new(),
+            cookies: Vec::new(),
+        };
+
+        assert_eq!(
+            CacheRoute::gen_query("page=2&sid=abc&filter_a=1&&filter_b", &key_rule),
+            "page=2&filter_a=1&filter_b"
+        );
+    }
+
+    #[test]
+    fn it_normalizes_path_with_key_rule() {
+        let mut key_rule = ConfigCacheKey {
+            path: Regex::new("^/").unwrap(),
+            query_include: Vec::new(),
+            query_exclude: Vec::new(),
+            query_sort: false,
+            path_case_fold: false,
+            ignore_version: false,
+            ignore_origin: false,
+            headers: Vec::new(),
+            cookies: Vec::new(),
+        };
+
+        assert_eq!(CacheRoute::gen_key_path("/Users/42", None), "/Users/42");
+        assert_eq!(
+            CacheRoute::gen_key_path("/Users/42", Some(&key_rule)),
+            "/Users/42"
+        );
+
+        key_rule.path_case_fold = true;
+
+        assert_eq!(
+            CacheRoute::gen_key_path("/Users/42", Some(&key_rule)),
+            "/users/42"
+        );
+    }
+
+    #[test]
+    fn it_matches_rule() {
+        let rule = ConfigCacheRule {
+            methods: vec!["GET".to_string(), "head".to_string()],
+            path: Regex::new("^/feed/").unwrap(),
+            host: Some("api.example.com".to_string()),
+            ttl: None,
+            ttl_status: HashMap::new(),
+            bypass: false,
+            buckets: Vec::new(),
+            max_body_size: None,
+            post: false,
+            post_canonical_json: false,
+            post_max_body_size: 1024,
+        };
+
+        assert!(CacheRoute::is_rule_match(
+            &rule,
+            &Method::Get,
+            "/feed/1",
+            Some("api.example.com")
+        ));
+        assert!(CacheRoute::is_rule_match(
+            &rule,
+            &Method::Head,
+            "/feed/1",
+            Some("API.example.com")
+        ));
+        assert!(!CacheRoute::is_rule_match(
+            &rule,
+            &Method::Options,
+            "/feed/1",
+            Some("api.example.com")
+        ));
+        assert!(!CacheRoute::is_rule_match(
+            &rule,
+            &Method::Get,
+            "/users/1",
+            Some("api.example.com")
+        ));
+        assert!(!CacheRoute::is_rule_match(
+            &rule,
+            &Method::Get,
+            "/feed/1",
+            None
+        ));
+    }
+
+    #[test]
+    fn it_generates_valid_variant_keys() {
+        let vary = vec!["accept-language".to_string()];
+        let (mut headers_en, mut headers_fr) = (Headers::new(), Headers::new());
+
+        headers_en.set_raw("Accept-Language", "en");
+        headers_fr.set_raw("Accept-Language", "fr");
+
+        let variant_hash_en = CacheRoute::gen_variant_hash(&vary, &headers_en);
+
+        assert_eq!(variant_hash_en.len(), ROUTE_HASH_SIZE);
+        assert_ne!(
+            variant_hash_en,
+            CacheRoute::gen_variant_hash(&vary, &headers_fr)
+        );
+        assert_eq!(
+            variant_hash_en,
+            CacheRoute::gen_variant_hash(&vary, &headers_en.clone())
+        );
+        assert_eq!(
+            CacheRoute::gen_key_variant_from_hash("bloom:0:c:dc56d17a:7cf7a048", "e7a274fb"),
+            "bloom:0:c:dc56d17a:7cf7a048:e7a274fb"
+        );
+    }
+
+    #[test]
+    fn it_generates_bucket_hierarchy() {
+        assert_eq!(
+            CacheRoute::gen_bucket_hierarchy(
+                vec![
+                    "user:42:posts".to_string(),
+                    "user:42".to_string(),
+                    "feed".to_string()
+                ],
+                ":"
+            ),
+            vec![
+                "feed".to_string(),
+                "user:*".to_string(),
+                "user:42".to_string(),
+                "user:42:*".to_string(),
+                "user:42:posts".to_string()
+            ]
+        );
+        assert_eq!(
+            CacheRoute::gen_bucket_hierarchy(vec!["a::b".to_string()], "::"),
+            vec!["a::*".to_string(), "a::b".to_string()]
+        );
+    }
+
+    #[test]
+    fn it_generates_valid_route_key() {
+        let route_hash = CacheRoute::gen_route_hash("dc56d17a", "/users/42");
+
+        assert_ne!(
+            route_hash,
+            CacheRoute::gen_route_hash("90d52bc6", "/users/42")
+        );
+        assert_eq!(
+            CacheRoute::gen_key_route_from_hash(0, &route_hash),
+            (format!("bloom:0:r:{route_hash}"), format!("r:{route_hash}"))
+        );
+    }
+
+    #[test]
+    fn it_generates_valid_entry_key() {
+        let (entry_key, entry_mask) =
+            CacheRoute::gen_key_entry(0, "dc56d17a", &Method::Get, "/users/42", Some("a=1"));
+
+        assert_eq!(entry_key, format!("bloom:0:{entry_mask}"));
+        assert!(entry_mask.starts_with("k:dc56d17a:"));
+        assert_ne!(
+            entry_mask,
+            CacheRoute::gen_key_entry(0, "dc56d17a", &Method::Head, "/users/42", Some("a=1")).1
+        );
+        assert_ne!(
+            entry_mask,
+            CacheRoute::gen_key_entry(0, "dc56d17a", &Method::Get, "/users/42", None).1
+        );
+    }
+
+    #[test]
+    fn it_generates_valid_lock_key() {
+        assert_eq!(
+            CacheRoute::gen_key_lock_from_mask(3, "dc56d17a:7cf7a048e7a274fb"),
+            "bloom:3:l:dc56d17a:7cf7a048e7a274fb"
+        );
+    }
 }
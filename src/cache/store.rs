@@ -5,24 +5,23 @@
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
 use brotli::{CompressorReader as BrotliCompressor, Decompressor as BrotliDecompressor};
-use futures::future::Future;
+use futures::future::{self, Future};
 use futures_cpupool::CpuPool;
-use r2d2::Pool;
-use redis::{self, Commands, Value};
 use std::cmp;
 use std::io::Read;
-use std::time::Duration;
-
-use super::route::ROUTE_PREFIX;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::backend::memory::CacheBackendMemoryBuilder;
+use super::backend::redis::CacheBackendRedisBuilder;
+use super::backend::redis_cluster::CacheBackendRedisClusterBuilder;
+use super::backend::{CacheBackend, CacheBackendInvalidation};
+use super::local::CacheLocal;
+use crate::config::config::{ConfigCacheBackend, ConfigRedisMode};
 use crate::APP_CONF;
 
 pub const BODY_COMPRESS_RATIO: u32 = 5;
 
-static KEY_BODY: &str = "b";
-static KEY_FINGERPRINT: &str = "f";
-static KEY_TAGS: &str = "t";
-static KEY_TAGS_SEPARATOR: &str = ",";
-
 lazy_static! {
     pub static ref EXECUTOR_POOL: CpuPool = CpuPool::new(APP_CONF.cache.executor_pool as usize);
 }
@@ -30,7 +29,35 @@ lazy_static! {
 pub struct CacheStoreBuilder;
 
 pub struct CacheStore {
-    pool: Pool<redis::Client>,
+    backend: Arc<dyn CacheBackend>,
+    local: Option<Arc<CacheLocal>>,
+}
+
+pub struct CacheStoreMeta {
+    pub fingerprint: String,
+    pub fresh_until: u64,
+    pub stale_until: u64,
+    pub vary: Vec<String>,
+}
+
+pub struct CacheStoreTTL {
+    pub fresh: usize,
+    pub stale: usize,
+    pub error: usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct CacheStoreDeadlines {
+    pub fresh_until: u64,
+    pub stale_until: u64,
+    pub expire_after: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CacheStoreFreshness {
+    Fresh,
+    Stale,
+    Expired,
 }
 
 #[derive(Debug)]
@@ -46,322 +73,358 @@ pub enum CacheStoreError {
 pub enum CachePurgeVariant {
     Bucket,
     Auth,
+    Route,
+    Key,
+    BucketSoft,
 }
 
-type CacheReadResultFuture = Box<dyn Future<Item = Option<String>, Error = CacheStoreError>>;
+type CacheReadMetaResultFuture =
+    Box<dyn Future<Item = Option<CacheStoreMeta>, Error = CacheStoreError>>;
+type CacheReadBodyResultFuture = Box<dyn Future<Item = Option<Vec<u8>>, Error = CacheStoreError>>;
 type CacheWriteResult = Result<String, (CacheStoreError, String)>;
 type CacheWriteResultFuture = Box<dyn Future<Item = CacheWriteResult, Error = ()>>;
+type CacheLockResultFuture = Box<dyn Future<Item = bool, Error = CacheStoreError>>;
 type CachePurgeResult = Result<(), CacheStoreError>;
 
-const MAX_I64_TTL: usize = i64::MAX as usize;
-
-fn safe_usize_to_i64(value: usize) -> i64 {
-    if value > MAX_I64_TTL {
-        i64::MAX
-    } else {
-        value as i64
-    }
-}
-
 impl CacheStoreBuilder {
     pub fn create() -> CacheStore {
-        info!(
-            "binding to store backend at {}:{}",
-            APP_CONF.redis.host, APP_CONF.redis.port
-        );
+        let backend: Arc<dyn CacheBackend> = match APP_CONF.cache.backend {
+            ConfigCacheBackend::Redis => match APP_CONF.redis.mode {
+                ConfigRedisMode::Standalone | ConfigRedisMode::Sentinel => {
+                    Arc::new(CacheBackendRedisBuilder::create())
+                }
+                ConfigRedisMode::Cluster => Arc::new(CacheBackendRedisClusterBuilder::create()),
+            },
+            ConfigCacheBackend::Memory => Arc::new(CacheBackendMemoryBuilder::create()),
+        };
+
+        // Spawn local cache in front of store backend? (invalidated from all Bloom instances)
+        let local = if APP_CONF.cache.local_max_size > 0 {
+            info!(
+                "enabling local cache with a budget of {} bytes",
+                APP_CONF.cache.local_max_size
+            );
+
+            let local = Arc::new(CacheLocal::new(
+                APP_CONF.cache.local_max_size,
+                APP_CONF.cache.local_ttl,
+            ));
+            let local_handler = local.clone();
+
+            backend.subscribe_invalidations(Box::new(move |invalidation| match invalidation {
+                CacheBackendInvalidation::All => local_handler.invalidate_all(),
+                CacheBackendInvalidation::Tag(key_tag) => local_handler.invalidate_tag(&key_tag),
+            }));
+
+            Some(local)
+        } else {
+            None
+        };
+
+        CacheStore::new(backend, local)
+    }
+}
 
-        let addr_auth = APP_CONF
-            .redis
-            .password
-            .as_ref()
-            .map_or_else(String::new, |password| format!(":{password}@"));
+impl CacheStoreMeta {
+    pub fn freshness(&self) -> CacheStoreFreshness {
+        Self::freshness_at(self.fresh_until, self.stale_until, Self::now())
+    }
 
-        let tcp_addr_raw = format!(
-            "redis://{}{}:{}/{}",
-            &addr_auth, APP_CONF.redis.host, APP_CONF.redis.port, APP_CONF.redis.database,
-        );
+    fn freshness_at(fresh_until: u64, stale_until: u64, now: u64) -> CacheStoreFreshness {
+        // Values stored without a freshness deadline never get stale. Past its stale \
+        //   deadline, a value is only kept around to be served if the API errors.
+        if fresh_until == 0 || now < fresh_until {
+            CacheStoreFreshness::Fresh
+        } else if now < stale_until {
+            CacheStoreFreshness::Stale
+        } else {
+            CacheStoreFreshness::Expired
+        }
+    }
 
-        debug!("will connect to redis at: {}", tcp_addr_raw);
-
-        match redis::Client::open(tcp_addr_raw.as_ref()) {
-            Ok(manager) => {
-                let builder = Pool::builder()
-                    .test_on_check_out(false)
-                    .max_size(APP_CONF.redis.pool_size)
-                    .max_lifetime(Some(Duration::from_secs(
-                        APP_CONF.redis.max_lifetime_seconds,
-                    )))
-                    .idle_timeout(Some(Duration::from_secs(
-                        APP_CONF.redis.idle_timeout_seconds,
-                    )))
-                    .connection_timeout(Duration::from_secs(
-                        APP_CONF.redis.connection_timeout_seconds,
-                    ));
-
-                match builder.build(manager) {
-                    Ok(pool) => {
-                        info!("bound to store backend");
-
-                        CacheStore { pool }
-                    }
-                    Err(e) => panic!("could not spawn redis pool: {e}"),
-                }
-            }
-            Err(e) => panic!("could not create redis connection manager: {e}"),
+    pub fn stale_at(fresh_until: u64, stale_until: u64, now: u64) -> Option<(u64, u64)> {
+        // Values that are not fresh anymore are left as-is. Fresh values get their stale grace \
+        //   window moved to start from now, so that they do not linger stale for longer.
+        if fresh_until == 0 {
+            Some((now, now))
+        } else if now < fresh_until {
+            Some((now, now + stale_until.saturating_sub(fresh_until)))
+        } else {
+            None
         }
     }
+
+    pub fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs())
+    }
 }
 
 impl CacheStore {
-    pub fn get_meta(&self, shard: u8, key: String) -> CacheReadResultFuture {
-        let pool = self.pool.clone();
+    pub fn new(backend: Arc<dyn CacheBackend>, local: Option<Arc<CacheLocal>>) -> Self {
+        Self { backend, local }
+    }
+
+    pub fn get_meta(&self, shard: u8, key: String) -> CacheReadMetaResultFuture {
+        // Hit in local cache? Serve from there (this does not touch the store backend)
+        // Notice: non-fresh values are always read from the store backend, as they may have \
+        //   been refreshed there by another Bloom instance.
+        if let Some(meta) = self
+            .local
+            .as_ref()
+            .and_then(|local| local.get_meta(&key))
+            .filter(|meta| meta.freshness() == CacheStoreFreshness::Fresh)
+        {
+            debug!("got meta from local cache for key: {}", &key);
+
+            return Box::new(future::ok(Some(meta)));
+        }
+
+        let (backend, local) = (self.backend.clone(), self.local.clone());
 
         Box::new(EXECUTOR_POOL.spawn_fn(move || {
-            get_cache_store_client_try!(pool, CacheStoreError::Disconnected, client {
-                match (*client).hget::<_, _, (Value, Value)>(key, (KEY_FINGERPRINT, KEY_TAGS)) {
-                    Ok(value) => {
-                        match value {
-                            (Value::BulkString(fingerprint_bytes), tags_bytes) => {
-                                // Parse tags and bump their last access time
-                                if let Value::BulkString(tags_bytes_data) = tags_bytes {
-                                    if let Ok(tags_data) = String::from_utf8(tags_bytes_data) {
-                                        if !tags_data.is_empty() {
-                                            let tags = tags_data.split(KEY_TAGS_SEPARATOR)
-                                                .map(|tag| {
-                                                    format!("{ROUTE_PREFIX}:{shard}:{tag}")
-                                                })
-                                                .collect::<Vec<String>>();
-
-                                            // Proceed a soft bump of last access time of \
-                                            //   associated tag keys. This prevents a \
-                                            //   frequently accessed cache namespace to \
-                                            //   become 'orphan' (ie. one or more tag keys \
-                                            //   are LRU-expired), and thus cache namespace \
-                                            //   not to be properly removed on purge of an \
-                                            //   associated tag.
-                                            // Also, count bumped keys. It may happen that \
-                                            //   some tag keys are incorrectly removed by \
-                                            //   Redis LRU system, as it is probabilistic \
-                                            //   and thus might sample some keys incorrectly.
-                                            // The conditions explained above only happens on \
-                                            //   Redis instances with used memory going over \
-                                            //   the threshold of the max memory policy.
-                                            let tags_count = tags.len();
-
-                                            match redis::cmd("TOUCH").arg(tags)
-                                                .query::<usize>(&mut *client) {
-                                                Ok(bump_count) => {
-                                                   // Partial bump count? Consider cache as \
-                                                   // non-existing
-                                                    if bump_count < tags_count {
-                                                        info!(
-                                                            "got only partial tag count: {}/{}",
-                                                            bump_count, tags_count
-                                                        );
-
-                                                        return Ok(None);
-                                                    }
-                                                },
-                                                Err(err) => {
-                                                    error!(
-                                                        "error bumping access time of tags: {}",
-                                                        err
-                                                    );
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-
-                                // Decode raw bytes to string
-                                String::from_utf8(fingerprint_bytes).map_or(Err(CacheStoreError::Corrupted), |fingerprint| Ok(Some(fingerprint)))
-                            },
-                            (Value::Nil, _) => Ok(None),
-                            _ => Err(CacheStoreError::Invalid),
-                        }
-                    },
-                    _ => Err(CacheStoreError::Failed),
+            Ok(backend.get_meta(shard, &key)?.map(|meta| {
+                // Notice: vary markers are not held in local cache, as they have no fingerprint.
+                if let Some(local) = local.as_ref().filter(|_| meta.vary.is_empty()) {
+                    local.set_meta(
+                        &key,
+                        &meta.fingerprint,
+                        &meta.tags,
+                        meta.fresh_until,
+                        meta.stale_until,
+                    );
                 }
-            })
+
+                CacheStoreMeta {
+                    fingerprint: meta.fingerprint,
+                    fresh_until: meta.fresh_until,
+                    stale_until: meta.stale_until,
+                    vary: meta.vary,
+                }
+            }))
         }))
     }
 
-    pub fn get_body(&self, key: String) -> CacheReadResultFuture {
-        let pool = self.pool.clone();
+    pub fn get_body(&self, key: String) -> CacheReadBodyResultFuture {
+        if let Some(body) = self.local.as_ref().and_then(|local| local.get_body(&key)) {
+            debug!("got body from local cache for key: {}", &key);
 
-        Box::new(EXECUTOR_POOL.spawn_fn(move || {
-            get_cache_store_client_try!(pool, CacheStoreError::Disconnected, client {
-                (*client).hget::<_, _, Value>(key, KEY_BODY).map_or(Err(CacheStoreError::Failed), |value| match value {
-                            Value::BulkString(body_bytes_raw) => {
-                                let body_bytes_result =
-                                if APP_CONF.cache.compress_body {
-                                    // Decompress raw bytes
-                                    let mut decompressor = BrotliDecompressor::new(
-                                        &body_bytes_raw[..], 4096
-                                    );
-
-                                    let mut decompress_bytes = Vec::new();
-
-                                    match decompressor.read_to_end(&mut decompress_bytes) {
-                                        Ok(_) => {
-                                            if !body_bytes_raw.is_empty() &&
-                                                    decompress_bytes.is_empty() {
-                                                error!(
-                                                    "decompressed store value has empty body"
-                                                );
-
-                                                Err(())
-                                            } else {
-                                                Ok(decompress_bytes)
-                                            }
-                                        },
-                                        Err(err) => {
-                                            error!("error decompressing store value: {}", err);
-
-                                            Err(())
-                                        }
-                                    }
-                                } else {
-                                    Ok(body_bytes_raw)
-                                };
-
-                                // Decode raw bytes to string
-                                body_bytes_result.map_or(Err(CacheStoreError::Failed), |body_bytes| String::from_utf8(body_bytes).map_or(Err(CacheStoreError::Corrupted), |body| Ok(Some(body))))
-                            },
-                            Value::Nil => Ok(None),
-                            _ => Err(CacheStoreError::Invalid),
-                        })
-            })
-        }))
+            return Box::new(future::ok(Some(body)));
+        }
+
+        let (backend, local) = (self.backend.clone(), self.local.clone());
+
+        Box::new(
+            EXECUTOR_POOL.spawn_fn(move || match backend.get_body(&key)? {
+                Some(body_bytes_raw) => {
+                    let body =
+                        Self::decompress_value(body_bytes_raw).or(Err(CacheStoreError::Failed))?;
+
+                    if let Some(ref local) = local {
+                        local.set_body(&key, &body);
+                    }
+
+                    Ok(Some(body))
+                }
+                None => Ok(None),
+            }),
+        )
     }
 
     pub fn set(
         &self,
         key: String,
         key_mask: String,
-        value: String,
+        value: Vec<u8>,
         fingerprint: String,
-        ttl: usize,
+        ttl: CacheStoreTTL,
         key_tags: Vec<(String, String)>,
     ) -> CacheWriteResultFuture {
-        let pool = self.pool.clone();
+        let (backend, local) = (self.backend.clone(), self.local.clone());
 
         Box::new(EXECUTOR_POOL.spawn_fn(move || {
-            Ok(get_cache_store_client_try!(
-                pool,
-                (CacheStoreError::Disconnected, fingerprint),
+            // Cap TTL to 'max_key_expiration'
+            let ttl_cap = cmp::min(ttl.fresh, APP_CONF.redis.max_key_expiration);
+
+            // Keep value around past its freshness deadline, so that it can be served stale \
+            //   while being revalidated, or when the API errors
+            let fresh_until = CacheStoreMeta::now() + ttl_cap as u64;
+
+            let deadlines = CacheStoreDeadlines {
+                fresh_until,
+                stale_until: fresh_until + ttl.stale as u64,
+                expire_after: ttl_cap.saturating_add(cmp::max(ttl.stale, ttl.error)),
+            };
+
+            // Ensure value is not larger than 'max_key_size'
+            if value.len() > APP_CONF.redis.max_key_size {
+                return Ok(Err((CacheStoreError::TooLarge, fingerprint)));
+            }
 
-                client {
-                    // Cap TTL to 'max_key_expiration'
-                    let ttl_cap = cmp::min(ttl, APP_CONF.redis.max_key_expiration);
+            // Compress value?
+            let store_value_bytes = match Self::compress_value(&value) {
+                Ok(store_value_bytes) => store_value_bytes,
+                Err(()) => {
+                    error!("error generating store value");
 
-                    // Ensure value is not larger than 'max_key_size'
-                    if value.len() > APP_CONF.redis.max_key_size {
-                        Err((CacheStoreError::TooLarge, fingerprint))
-                    } else {
-                        // Compress value?
-                        let store_value_bytes_result = if APP_CONF.cache.compress_body {
-                            let mut compressor = BrotliCompressor::new(
-                                value.as_bytes(), 4096, BODY_COMPRESS_RATIO, 22
-                            );
-
-                            let mut compress_bytes = Vec::new();
-
-                            match compressor.read_to_end(&mut compress_bytes) {
-                                Ok(_) => Ok(compress_bytes),
-                                Err(err) => {
-                                    error!("error compressing store value: {}", err);
-
-                                    Err(())
-                                }
-                            }
-                        } else {
-                            Ok(value.into_bytes())
-                        };
-
-                        if let Ok(store_value_bytes) = store_value_bytes_result {
-                            let mut pipeline = redis::pipe();
-
-                            // Append storage command
-                            {
-                                let key_tag_masks = key_tags.iter()
-                                    .map(|key_tag| key_tag.1.as_ref())
-                                    .collect::<Vec<&str>>();
-
-                                pipeline.hset_multiple(
-                                    &key, &[
-                                        (
-                                            KEY_FINGERPRINT,
-                                            fingerprint.as_bytes()
-                                        ),
-
-                                        (
-                                            KEY_TAGS,
-                                            key_tag_masks.join(KEY_TAGS_SEPARATOR).as_bytes()
-                                        ),
-
-                                        (
-                                            KEY_BODY,
-                                            &store_value_bytes
-                                        )
-                                    ]
-                                ).ignore();
-                            }
-
-                            pipeline.expire(&key, safe_usize_to_i64(ttl_cap)).ignore();
-
-                            for key_tag in key_tags {
-                                pipeline.sadd(&key_tag.0, &key_mask).ignore();
-                                pipeline.expire(&key_tag.0, safe_usize_to_i64(APP_CONF.redis.max_key_expiration));
-                            }
-
-                            // Bucket (MULTI operation for main data + bucket marker)
-                            match pipeline.query::<()>(&mut *client) {
-                                Ok(()) => Ok(fingerprint),
-                                Err(err) => {
-                                    error!("got store error: {}", err);
-
-                                    Err((CacheStoreError::Failed, fingerprint))
-                                }
-                            }
-                        } else {
-                            error!("error generating store value");
-
-                            Err((CacheStoreError::Failed, fingerprint))
+                    return Ok(Err((CacheStoreError::Failed, fingerprint)));
+                }
+            };
+
+            Ok(
+                match backend.set(
+                    &key,
+                    &key_mask,
+                    &store_value_bytes,
+                    &fingerprint,
+                    &deadlines,
+                    &key_tags,
+                ) {
+                    Ok(()) => {
+                        if let Some(ref local) = local {
+                            let tags = key_tags
+                                .into_iter()
+                                .map(|key_tag| key_tag.0)
+                                .collect::<Vec<String>>();
+
+                            local.set(&key, &fingerprint, &tags, &value, &deadlines);
                         }
+
+                        Ok(fingerprint)
                     }
+                    Err(err) => Err((err, fingerprint)),
+                },
+            )
+        }))
+    }
+
+    pub fn set_vary(&self, key: String, vary: Vec<String>) {
+        let backend = self.backend.clone();
+
+        // Nothing waits on vary marker writes, a failed one is recovered on next write
+        EXECUTOR_POOL
+            .spawn_fn(move || {
+                if let Err(err) = backend.set_vary(&key, &vary, APP_CONF.redis.max_key_expiration) {
+                    warn!("could not store vary marker: {} because: {:?}", key, err);
                 }
-            ))
+
+                Ok::<(), ()>(())
+            })
+            .forget();
+    }
+
+    pub fn acquire_lock(&self, key: String, token: String) -> CacheLockResultFuture {
+        let backend = self.backend.clone();
+
+        Box::new(EXECUTOR_POOL.spawn_fn(move || {
+            backend.acquire_lock(&key, &token, APP_CONF.cache.coalesce_lock_timeout)
         }))
     }
 
+    pub fn release_lock(&self, key: String, token: String) {
+        let backend = self.backend.clone();
+
+        // Nothing waits on lock releases, which must still happen if their future gets dropped
+        EXECUTOR_POOL
+            .spawn_fn(move || {
+                if let Err(err) = backend.release_lock(&key, &token) {
+                    warn!("could not release lock: {} because: {:?}", key, err);
+                }
+
+                Ok::<(), ()>(())
+            })
+            .forget();
+    }
+
+    pub fn has_lock(&self, key: String) -> CacheLockResultFuture {
+        let backend = self.backend.clone();
+
+        Box::new(EXECUTOR_POOL.spawn_fn(move || backend.has_lock(&key)))
+    }
+
     pub fn purge_tag(
         &self,
         variant: &CachePurgeVariant,
         shard: u8,
         key_tag: &str,
     ) -> CachePurgeResult {
-        get_cache_store_client_wait!(self.pool, CacheStoreError::Disconnected, client {
-            // Invoke keyspace cleanup script for key tag
-            let result = redis::Script::new(variant.get_script())
-                .arg(ROUTE_PREFIX)
-                .arg(shard)
-                .arg(key_tag)
-                .invoke::<()>(&mut *client);
-
-            result
-                .and(Ok(()))
-                .or(Err(CacheStoreError::Failed))
-        })
+        self.backend.purge_tag(variant, shard, key_tag)?;
+
+        // Invalidate local caches, starting with the one from this Bloom instance
+        if let Some(ref local) = self.local {
+            local.invalidate_tag(key_tag);
+        }
+
+        // Notice: always notify other Bloom instances, as they may have a local cache \
+        //   enabled even if this instance does not.
+        if let Err(err) = self.backend.publish_invalidation(key_tag) {
+            warn!(
+                "could not publish invalidation for tag: {} because: {:?}",
+                key_tag, err
+            );
+        }
+
+        Ok(())
+    }
+
+    fn compress_value(value: &[u8]) -> Result<Vec<u8>, ()> {
+        if APP_CONF.cache.compress_body {
+            let mut compressor = BrotliCompressor::new(value, 4096, BODY_COMPRESS_RATIO, 22);
+
+            let mut compress_bytes = Vec::new();
+
+            match compressor.read_to_end(&mut compress_bytes) {
+                Ok(_) => Ok(compress_bytes),
+                Err(err) => {
+                    error!("error compressing store value: {}", err);
+
+                    Err(())
+                }
+            }
+        } else {
+            Ok(value.to_vec())
+        }
+    }
+
+    fn decompress_value(value: Vec<u8>) -> Result<Vec<u8>, ()> {
+        if APP_CONF.cache.compress_body {
+            let mut decompressor = BrotliDecompressor::new(&value[..], 4096);
+
+            let mut decompress_bytes = Vec::new();
+
+            match decompressor.read_to_end(&mut decompress_bytes) {
+                Ok(_) => {
+                    if !value.is_empty() && decompress_bytes.is_empty() {
+                        error!("decompressed store value has empty body");
+
+                        Err(())
+                    } else {
+                        Ok(decompress_bytes)
+                    }
+                }
+                Err(err) => {
+                    error!("error decompressing store value: {}", err);
+
+                    Err(())
+                }
+            }
+        } else {
+            Ok(value)
+        }
     }
 }
 
 impl CachePurgeVariant {
-    const fn get_script(&self) -> &'static str {
+    pub const fn is_soft(&self) -> bool {
+        matches!(*self, Self::BucketSoft)
+    }
+
+    pub const fn get_script(&self) -> Option<&'static str> {
         // Notice: there is a limit of 1000 purgeable tags per bucket. Purging a lot of tags at \
         //   once is dangerous for Bloom, as the underlying Redis server is at risk of blocking.
         match *self {
-            Self::Bucket | Self::Auth => {
+            Self::Bucket | Self::Auth | Self::Route | Self::Key => Some(
                 r#"
                   local batch_size = 1000
                   local cursor = "0"
@@ -381,8 +444,51 @@ impl CachePurgeVariant {
                   until cursor == "0"
 
                   redis.call('UNLINK', ARGV[3])
-                "#
-            }
+                "#,
+            ),
+            // Notice: soft purges keep cache keys and their tag set, and mark each cache key \
+            //   as stale with the stale mark script, which is shared by all Redis backends.
+            Self::BucketSoft => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_computes_freshness() {
+        assert_eq!(
+            CacheStoreMeta::freshness_at(0, 0, 1000),
+            CacheStoreFreshness::Fresh
+        );
+        assert_eq!(
+            CacheStoreMeta::freshness_at(1000, 1030, 999),
+            CacheStoreFreshness::Fresh
+        );
+        assert_eq!(
+            CacheStoreMeta::freshness_at(1000, 1030, 1000),
+            CacheStoreFreshness::Stale
+        );
+        assert_eq!(
+            CacheStoreMeta::freshness_at(1000, 1030, 1030),
+            CacheStoreFreshness::Expired
+        );
+        assert_eq!(
+            CacheStoreMeta::freshness_at(1000, 0, 1000),
+            CacheStoreFreshness::Expired
+        );
+    }
+
+    #[test]
+    fn it_computes_stale_deadlines() {
+        assert_eq!(CacheStoreMeta::stale_at(0, 0, 1000), Some((1000, 1000)));
+        assert_eq!(
+            CacheStoreMeta::stale_at(1600, 1630, 1000),
+            Some((1000, 1030))
+        );
+        assert_eq!(CacheStoreMeta::stale_at(1000, 1030, 1000), None);
+        assert_eq!(CacheStoreMeta::stale_at(900, 1030, 1000), None);
+    }
+}
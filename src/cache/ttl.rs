@@ -0,0 +1,289 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use hyper::header::{CacheControl, CacheDirective, Date, Expires};
+use hyper::{Headers, StatusCode};
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use crate::config::config::ConfigCacheRule;
+use crate::header::response_ttl::HeaderResponseBloomResponseTTL;
+use crate::APP_CONF;
+
+pub struct CacheTTL;
+
+impl CacheTTL {
+    pub fn from_response(
+        status: StatusCode,
+        headers: &Headers,
+        rule: Option<&ConfigCacheRule>,
+    ) -> usize {
+        // Acquire TTL from response, or fallback to route rule and status TTLs, or to default TTL
+        // Notice: 'Bloom-Response-TTL' always wins, as it is specific to Bloom, followed by \
+        //   'Surrogate-Control' as it targets surrogates such as Bloom, while route rule and \
+        //   status TTLs win over HTTP caching headers.
+        let ttl = headers
+            .get::<HeaderResponseBloomResponseTTL>()
+            .map(|value| value.0)
+            .or_else(|| {
+                if APP_CONF.cache.honor_cdn_headers {
+                    Self::from_surrogate_control(headers)
+                } else {
+                    None
+                }
+            })
+            .or_else(|| Self::from_rules(status, rule));
+
+        if APP_CONF.cache.honor_http_caching {
+            ttl.or_else(|| Self::from_http(headers, SystemTime::now()))
+        } else {
+            ttl
+        }
+        .unwrap_or(APP_CONF.cache.ttl_default)
+    }
+
+    pub fn from_rules(status: StatusCode, rule: Option<&ConfigCacheRule>) -> Option<usize> {
+        Self::from_rules_map(status, rule, &APP_CONF.cache.ttl_status)
+    }
+
+    fn from_rules_map(
+        status: StatusCode,
+        rule: Option<&ConfigCacheRule>,
+        ttl_status: &HashMap<String, usize>,
+    ) -> Option<usize> {
+        // Route rule TTLs win over global status TTLs, as they are specific to their routes \
+        //   (status TTLs from the route rule first, and then the route rule TTL)
+        rule.and_then(|rule| Self::from_status_map(&rule.ttl_status, status).or(rule.ttl))
+            .or_else(|| Self::from_status_map(ttl_status, status))
+    }
+
+    fn from_status_map(ttl_status: &HashMap<String, usize>, status: StatusCode) -> Option<usize> {
+        // Exact status codes (eg. '404') win over status classes (eg. '4xx')
+        let code = u16::from(status);
+
+        ttl_status
+            .get(&code.to_string())
+            .or_else(|| ttl_status.get(&format!("{}xx", code / 100)))
+            .copied()
+    }
+
+    fn from_surrogate_control(headers: &Headers) -> Option<usize> {
+        // Directives may be targeted at a given surrogate (eg. 'max-age=60;bloom'), \
+        //   though all surrogates are considered the same there.
+        headers
+            .get_raw("Surrogate-Control")?
+            .iter()
+            .find_map(|line| {
+                String::from_utf8_lossy(line)
+                    .split(',')
+                    .find_map(|directive| {
+                        let directive = directive.split(';').next().unwrap_or("").trim();
+
+                        match directive.split_once('=') {
+                            Some((name, value)) if name.trim().eq_ignore_ascii_case("max-age") => {
+                                value.trim().trim_matches('"').parse().ok()
+                            }
+                            _ => None,
+                        }
+                    })
+            })
+    }
+
+    fn from_http(headers: &Headers, now: SystemTime) -> Option<usize> {
+        // Shared caches prefer 's-maxage' over 'max-age', which both prefer over 'Expires'
+        Self::from_cache_control(headers).or_else(|| Self::from_expires(headers, now))
+    }
+
+    fn from_cache_control(headers: &Headers) -> Option<usize> {
+        let cache_control = headers.get::<CacheControl>()?;
+
+        let (mut max_age, mut s_max_age) = (None, None);
+
+        for cache_directive in cache_control.iter() {
+            match *cache_directive {
+                CacheDirective::SMaxAge(seconds) => s_max_age = Some(seconds as usize),
+                CacheDirective::MaxAge(seconds) => max_age = Some(seconds as usize),
+                _ => {}
+            }
+        }
+
+        s_max_age.or(max_age)
+    }
+
+    fn from_expires(headers: &Headers, now: SystemTime) -> Option<usize> {
+        let expires = match headers.get::<Expires>() {
+            Some(expires) => SystemTime::from(expires.0),
+            None => {
+                // An invalid 'Expires' value (eg. '0') means that the response is already expired
+                return headers.get_raw("Expires").map(|_| 0);
+            }
+        };
+
+        // Expiration is relative to the origin clock, if known
+        let date = headers
+            .get::<Date>()
+            .map_or(now, |date| SystemTime::from(date.0));
+
+        Some(
+            expires
+                .duration_since(date)
+                .map_or(0, |duration| duration.as_secs() as usize),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::check::CacheCheck;
+    use regex::Regex;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn it_acquires_http_ttl() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let mut headers = Headers::new();
+
+        assert_eq!(CacheTTL::from_http(&headers, now), None);
+
+        headers.set_raw("Expires", "Fri, 14 Jul 2017 03:00:00 GMT");
+
+        assert_eq!(CacheTTL::from_http(&headers, now), Some(1200));
+
+        headers.set_raw("Date", "Fri, 14 Jul 2017 02:50:00 GMT");
+
+        assert_eq!(CacheTTL::from_http(&headers, now), Some(600));
+
+        headers.set_raw("Cache-Control", "public, max-age=60");
+
+        assert_eq!(CacheTTL::from_http(&headers, now), Some(60));
+
+        headers.set_raw("Cache-Control", "s-maxage=300, max-age=60");
+
+        assert_eq!(CacheTTL::from_http(&headers, now), Some(300));
+    }
+
+    #[test]
+    fn it_acquires_surrogate_ttl() {
+        let mut headers = Headers::new();
+
+        assert_eq!(CacheTTL::from_surrogate_control(&headers), None);
+
+        headers.set_raw("Surrogate-Control", "content=\"ESI/1.0\", max-age=3600");
+
+        assert_eq!(CacheTTL::from_surrogate_control(&headers), Some(3600));
+
+        headers.set_raw("Surrogate-Control", "max-age=60;bloom");
+
+        assert_eq!(CacheTTL::from_surrogate_control(&headers), Some(60));
+
+        headers.set_raw("Surrogate-Control", "no-store");
+
+        assert_eq!(CacheTTL::from_surrogate_control(&headers), None);
+    }
+
+    #[test]
+    fn it_acquires_status_ttl() {
+        let ttl_status: HashMap<String, usize> = [
+            ("200".to_string(), 600),
+            ("401".to_string(), 0),
+            ("4xx".to_string(), 30),
+        ]
+        .into();
+
+        assert_eq!(
+            CacheTTL::from_status_map(&ttl_status, StatusCode::Ok),
+            Some(600)
+        );
+        assert_eq!(
+            CacheTTL::from_status_map(&ttl_status, StatusCode::Unauthorized),
+            Some(0)
+        );
+        assert_eq!(
+            CacheTTL::from_status_map(&ttl_status, StatusCode::NotFound),
+            Some(30)
+        );
+        assert_eq!(
+            CacheTTL::from_status_map(&ttl_status, StatusCode::MovedPermanently),
+            None
+        );
+    }
+
+    #[test]
+    fn it_expires_invalid_http_ttl() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let mut headers = Headers::new();
+
+        headers.set_raw("Expires", "0");
+
+        assert_eq!(CacheTTL::from_http(&headers, now), Some(0));
+
+        headers.set_raw("Expires", "Thu, 01 Jan 1970 00:00:00 GMT");
+
+        assert_eq!(CacheTTL::from_http(&headers, now), Some(0));
+    }
+
+    #[test]
+    fn it_does_not_cache_zero_http_ttl() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let mut headers = Headers::new();
+
+        headers.set_raw("Cache-Control", "public, max-age=0");
+
+        assert_eq!(CacheTTL::from_http(&headers, now), Some(0));
+
+        headers.set_raw("Cache-Control", "s-maxage=0, max-age=60");
+
+        assert_eq!(CacheTTL::from_http(&headers, now), Some(0));
+
+        let ttl = CacheTTL::from_http(&headers, now).unwrap();
+
+        assert!(!CacheCheck::from_policy(None, ttl, 0), "max-age=0");
+    }
+
+    #[test]
+    fn it_prefers_rule_ttl_over_global_status_ttl() {
+        let ttl_status: HashMap<String, usize> = [("2xx".to_string(), 600)].into();
+
+        let mut rule = ConfigCacheRule {
+            methods: Vec::new(),
+            path: Regex::new("^/hot").unwrap(),
+            host: None,
+            ttl: Some(5),
+            ttl_status: HashMap::new(),
+            bypass: false,
+            buckets: Vec::new(),
+            max_body_size: None,
+            post: false,
+            post_canonical_json: false,
+            post_max_body_size: 1024,
+        };
+
+        assert_eq!(
+            CacheTTL::from_rules_map(StatusCode::Ok, None, &ttl_status),
+            Some(600)
+        );
+        assert_eq!(
+            CacheTTL::from_rules_map(StatusCode::Ok, Some(&rule), &ttl_status),
+            Some(5)
+        );
+
+        rule.ttl_status = [("200".to_string(), 30)].into();
+
+        assert_eq!(
+            CacheTTL::from_rules_map(StatusCode::Ok, Some(&rule), &ttl_status),
+            Some(30)
+        );
+
+        rule.ttl = None;
+        rule.ttl_status = HashMap::new();
+
+        assert_eq!(
+            CacheTTL::from_rules_map(StatusCode::Ok, Some(&rule), &ttl_status),
+            Some(600)
+        );
+    }
+}
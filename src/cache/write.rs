@@ -6,20 +6,25 @@
 
 use farmhash;
 use futures::{future, Future, Stream};
-use hyper::{Body, Error, Headers, HttpVersion, Method, StatusCode};
+use hyper::header::{CacheControl, CacheDirective, Vary};
+use hyper::{Body, Error, Headers, StatusCode};
 
 use super::check::CacheCheck;
+use super::context::CacheContext;
+use super::envelope::CacheEnvelope;
+use super::invalidate::CacheInvalidate;
 use super::route::CacheRoute;
+use super::store::CacheStoreTTL;
+use super::ttl::CacheTTL;
 use crate::header::janitor::HeaderJanitor;
 use crate::header::response_buckets::HeaderResponseBloomResponseBuckets;
-use crate::header::response_ttl::HeaderResponseBloomResponseTTL;
 use crate::APP_CACHE_STORE;
 use crate::APP_CONF;
 
 pub struct CacheWrite;
 
 pub struct CacheWriteResult {
-    pub body: Result<String, Option<String>>,
+    pub body: Result<Vec<u8>, Option<Vec<u8>>>,
     pub fingerprint: Option<String>,
     pub status: StatusCode,
     pub headers: Headers,
@@ -29,131 +34,257 @@ pub type CacheWriteResultFuture = Box<dyn Future<Item = CacheWriteResult, Error
 
 impl CacheWrite {
     pub fn save(
-        key: String,
-        key_mask: String,
-        auth_hash: String,
-        shard: u8,
-        method: Method,
-        version: HttpVersion,
+        context: CacheContext,
         status: StatusCode,
         mut headers: Headers,
         body: Body,
     ) -> CacheWriteResultFuture {
-        Box::new(
-            body.concat2()
-                .map(|raw_data| String::from_utf8(raw_data.to_vec()))
-                .and_then(move |body_result| {
-                    if let Ok(body_value) = body_result {
-                        debug!("checking whether to write cache for key: {}", &key);
-
-                        if !APP_CONF.cache.disable_write
-                            && CacheCheck::from_response(&method, status, &headers)
-                        {
-                            debug!("key: {} cacheable, writing cache", &key);
-
-                            // Acquire bucket from response, or fallback to no bucket
-                            let mut key_tags = headers
-                                .get::<HeaderResponseBloomResponseBuckets>()
-                                .map_or_else(Vec::new, |value| {
-                                    value
-                                        .0
-                                        .iter()
-                                        .map(|value| {
-                                            CacheRoute::gen_key_bucket_from_hash(
-                                                shard,
-                                                &CacheRoute::hash(value),
-                                            )
-                                        })
-                                        .collect::<Vec<(String, String)>>()
-                                });
-
-                            key_tags.push(CacheRoute::gen_key_auth_from_hash(shard, &auth_hash));
-
-                            // Acquire TTL from response, or fallback to default TTL
-                            let ttl = headers
-                                .get::<HeaderResponseBloomResponseTTL>()
-                                .map_or_else(|| APP_CONF.cache.ttl_default, |value| value.0);
-
-                            // Clean headers before they get stored
-                            HeaderJanitor::clean(&mut headers);
-
-                            // Generate storable value
-                            let body_string = format!(
-                                "{}\n{}\n{}",
-                                Self::generate_chain_banner(&version, &status),
-                                Self::generate_chain_headers(&headers),
-                                body_value
-                            );
-
-                            // Process value fingerprint
-                            let fingerprint = Self::process_body_fingerprint(&body_string);
-
-                            // Write to cache
-                            Box::new(
-                                APP_CACHE_STORE
-                                    .set(key, key_mask, body_string, fingerprint, ttl, key_tags)
-                                    .or_else(|()| Err(Error::Incomplete))
-                                    .and_then(move |result| {
-                                        future::ok(match result {
-                                            Ok(fingerprint) => {
-                                                debug!("wrote cache");
-
-                                                CacheWriteResult {
-                                                    body: Ok(body_value),
-                                                    fingerprint: Some(fingerprint),
-                                                    status,
-                                                    headers,
-                                                }
-                                            }
-                                            Err(forward) => {
-                                                warn!(
-                                                    "could not write cache because: {:?}",
-                                                    forward.0
-                                                );
-
-                                                CacheWriteResult {
-                                                    body: Err(Some(body_value)),
-                                                    fingerprint: Some(forward.1),
-                                                    status,
-                                                    headers,
-                                                }
-                                            }
-                                        })
-                                    }),
-                            )
-                        } else {
-                            debug!("key: {} not cacheable, ignoring", &key);
-
-                            // Not cacheable, ignore
-                            Self::result_cache_write_error(Some(body_value), status, headers)
-                        }
-                    } else {
-                        error!("failed unwrapping body value for key: {}, ignoring", &key);
-
-                        Self::result_cache_write_error(None, status, headers)
+        let CacheContext {
+            shard,
+            auth_hash,
+            key,
+            key_mask,
+            method,
+            uri,
+            headers: req_headers,
+            rule,
+        } = context;
+
+        // Response varies on request headers? Value will be stored for this request variant
+        let vary = Self::acquire_vary(&headers);
+        let variant_hash =
+            (!vary.is_empty()).then(|| CacheRoute::gen_variant_hash(&vary, &req_headers));
+
+        // Tag value with its entry, so that all its variants can be purged at once (if enabled)
+        let entry_tag = APP_CONF.cache.purge_entries.then(|| {
+            CacheRoute::gen_key_entry(shard, &auth_hash, &method, uri.path(), uri.query())
+        });
+
+        // Tag value with its route, if it may get invalidated by mutating requests on this route
+        let route_hash = CacheInvalidate::has_rules()
+            .then(|| CacheRoute::gen_route_hash(&auth_hash, uri.path()));
+
+        Box::new(body.concat2().and_then(move |raw_data| {
+            // Body is kept as raw bytes, as it may not be valid UTF-8 (eg. images)
+            let body_value = raw_data.to_vec();
+
+            let ttl = CacheTTL::from_response(status, &headers, rule);
+
+            debug!("checking whether to write cache for key: {}", &key);
+
+            if !APP_CONF.cache.disable_write
+                && CacheCheck::from_response(&method, rule, status, &headers)
+                && CacheCheck::from_policy(rule, ttl, body_value.len())
+            {
+                debug!("key: {} cacheable, writing cache", &key);
+
+                // Mark route as varying, so that reads can find the value for their variant
+                let (key, key_mask) = match variant_hash {
+                    Some(variant_hash) => {
+                        APP_CACHE_STORE.set_vary(key.to_owned(), vary);
+
+                        (
+                            CacheRoute::gen_key_variant_from_hash(&key, &variant_hash),
+                            CacheRoute::gen_key_variant_from_hash(&key_mask, &variant_hash),
+                        )
                     }
-                }),
-        )
-    }
+                    None => (key, key_mask),
+                };
+
+                // Acquire bucket from response, or fallback to no bucket
+                let mut buckets = Self::acquire_buckets(&headers);
+
+                // Append default buckets from route rule
+                if let Some(rule) = rule {
+                    buckets.extend(rule.buckets.iter().cloned());
+                }
+
+                // Tag with ancestor buckets, so that a whole hierarchy can be purged at once
+                if let Some(ref separator) = APP_CONF.cache.bucket_separator {
+                    buckets = CacheRoute::gen_bucket_hierarchy(buckets, separator);
+                }
+
+                let mut key_tags = buckets
+                    .iter()
+                    .map(|bucket| {
+                        CacheRoute::gen_key_bucket_from_hash(shard, &CacheRoute::hash(bucket))
+                    })
+                    .collect::<Vec<(String, String)>>();
+
+                key_tags.push(CacheRoute::gen_key_auth_from_hash(shard, &auth_hash));
+                if let Some(entry_tag) = entry_tag {
+                    key_tags.push(entry_tag);
+                }
 
-    fn generate_chain_banner(version: &HttpVersion, status: &StatusCode) -> String {
-        format!("{version} {status}")
+                if let Some(route_hash) = route_hash {
+                    key_tags.push(CacheRoute::gen_key_route_from_hash(shard, &route_hash));
+                }
+
+                // Acquire stale grace window from response, or fallback to default grace window
+                let ttl_stale =
+                    Self::acquire_cache_control_seconds(&headers, "stale-while-revalidate")
+                        .unwrap_or(APP_CONF.cache.stale_while_revalidate);
+
+                // Acquire error grace window from response, or fallback to default grace window
+                let ttl_error = Self::acquire_cache_control_seconds(&headers, "stale-if-error")
+                    .unwrap_or(APP_CONF.cache.stale_if_error);
+
+                // Clean headers before they get stored
+                HeaderJanitor::clean(&mut headers);
+
+                // Generate storable value
+                let body_bytes = CacheEnvelope {
+                    status: u16::from(status),
+                    headers: Self::generate_envelope_headers(&headers),
+                    body: body_value.to_owned(),
+                }
+                .encode();
+
+                // Process value fingerprint
+                let fingerprint = Self::process_body_fingerprint(&body_bytes);
+
+                // Write to cache
+                Box::new(
+                    APP_CACHE_STORE
+                        .set(
+                            key,
+                            key_mask,
+                            body_bytes,
+                            fingerprint,
+                            CacheStoreTTL {
+                                fresh: ttl,
+                                stale: ttl_stale,
+                                error: ttl_error,
+                            },
+                            key_tags,
+                        )
+                        .or_else(|()| Err(Error::Incomplete))
+                        .and_then(move |result| {
+                            future::ok(match result {
+                                Ok(fingerprint) => {
+                                    debug!("wrote cache");
+
+                                    CacheWriteResult {
+                                        body: Ok(body_value),
+                                        fingerprint: Some(fingerprint),
+                                        status,
+                                        headers,
+                                    }
+                                }
+                                Err(forward) => {
+                                    warn!("could not write cache because: {:?}", forward.0);
+
+                                    CacheWriteResult {
+                                        body: Err(Some(body_value)),
+                                        fingerprint: Some(forward.1),
+                                        status,
+                                        headers,
+                                    }
+                                }
+                            })
+                        }),
+                )
+            } else {
+                debug!("key: {} not cacheable, ignoring", &key);
+
+                // Not cacheable, ignore
+                Self::result_cache_write_error(Some(body_value), status, headers)
+            }
+        }))
     }
 
-    fn generate_chain_headers(headers: &Headers) -> String {
+    fn generate_envelope_headers(headers: &Headers) -> Vec<(String, Vec<u8>)> {
+        // Store each raw header line verbatim, as some headers may appear multiple times
         headers
             .iter()
             .filter(|header_view| !HeaderJanitor::is_contextual(header_view))
-            .map(|header_view| format!("{}: {}\n", header_view.name(), header_view.value_string()))
+            .flat_map(|header_view| {
+                header_view
+                    .raw()
+                    .iter()
+                    .map(|line| (header_view.name().to_string(), line.to_vec()))
+                    .collect::<Vec<(String, Vec<u8>)>>()
+            })
             .collect()
     }
 
-    fn process_body_fingerprint(body_string: &str) -> String {
-        format!("{:x}", farmhash::fingerprint64(body_string.as_bytes()))
+    fn acquire_buckets(headers: &Headers) -> Vec<String> {
+        let mut buckets = headers
+            .get::<HeaderResponseBloomResponseBuckets>()
+            .map_or_else(Vec::new, |value| value.0.to_owned());
+
+        if APP_CONF.cache.honor_cdn_headers {
+            buckets.extend(Self::acquire_cdn_buckets(headers));
+
+            buckets.sort();
+            buckets.dedup();
+        }
+
+        buckets
+    }
+
+    fn acquire_cdn_buckets(headers: &Headers) -> Vec<String> {
+        let mut buckets = Vec::new();
+
+        // CDN tag headers also hold buckets, as 'Surrogate-Key' (space-separated, eg. Fastly) \
+        //   and 'Cache-Tag' (comma-separated, eg. Cloudflare)
+        for (header_name, separator) in [("Surrogate-Key", ' '), ("Cache-Tag", ',')] {
+            if let Some(raw) = headers.get_raw(header_name) {
+                for line in raw.iter() {
+                    buckets.extend(
+                        String::from_utf8_lossy(line)
+                            .split(separator)
+                            .map(str::trim)
+                            .filter(|bucket| !bucket.is_empty())
+                            .map(str::to_string),
+                    );
+                }
+            }
+        }
+
+        buckets
+    }
+
+    fn acquire_vary(headers: &Headers) -> Vec<String> {
+        // Header names are case-insensitive, and their order does not matter
+        match headers.get::<Vary>() {
+            Some(Vary::Items(header_names)) => {
+                let mut vary = header_names
+                    .iter()
+                    .map(|header_name| header_name.to_lowercase())
+                    .collect::<Vec<String>>();
+
+                vary.sort();
+                vary.dedup();
+
+                vary
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn acquire_cache_control_seconds(headers: &Headers, directive: &str) -> Option<usize> {
+        // Non-standard directives (eg. 'stale-while-revalidate') are parsed as extensions
+        headers.get::<CacheControl>().and_then(|cache_control| {
+            cache_control
+                .iter()
+                .find_map(|cache_directive| match cache_directive {
+                    CacheDirective::Extension(name, Some(value))
+                        if name.eq_ignore_ascii_case(directive) =>
+                    {
+                        value.trim_matches('"').parse().ok()
+                    }
+                    _ => None,
+                })
+        })
+    }
+
+    fn process_body_fingerprint(body_bytes: &[u8]) -> String {
+        format!("{:x}", farmhash::fingerprint64(body_bytes))
     }
 
     fn result_cache_write_error(
-        body: Option<String>,
+        body: Option<Vec<u8>>,
         status: StatusCode,
         headers: Headers,
     ) -> CacheWriteResultFuture {
@@ -169,17 +300,83 @@ impl CacheWrite {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use hyper::Method;
+
+    #[test]
+    fn it_acquires_cache_control_seconds() {
+        let mut headers = Headers::new();
+
+        headers.set_raw("Cache-Control", "max-age=60, stale-while-revalidate=30");
+
+        assert_eq!(
+            CacheWrite::acquire_cache_control_seconds(&headers, "stale-while-revalidate"),
+            Some(30)
+        );
+        assert_eq!(
+            CacheWrite::acquire_cache_control_seconds(&headers, "stale-if-error"),
+            None
+        );
+
+        headers.set_raw("Cache-Control", "max-age=60, Stale-If-Error=\"86400\"");
+
+        assert_eq!(
+            CacheWrite::acquire_cache_control_seconds(&headers, "stale-if-error"),
+            Some(86400)
+        );
+    }
+
+    #[test]
+    fn it_acquires_cdn_buckets() {
+        let mut headers = Headers::new();
+
+        assert!(CacheWrite::acquire_cdn_buckets(&headers).is_empty());
+
+        headers.set_raw("Surrogate-Key", "user:42  posts");
+        headers.set_raw("Cache-Tag", "feed, user:42,");
+
+        assert_eq!(
+            CacheWrite::acquire_cdn_buckets(&headers),
+            vec![
+                "user:42".to_string(),
+                "posts".to_string(),
+                "feed".to_string(),
+                "user:42".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn it_acquires_vary() {
+        let mut headers = Headers::new();
+
+        assert!(CacheWrite::acquire_vary(&headers).is_empty());
+
+        headers.set_raw("Vary", "Accept-Language, accept, Accept");
+
+        assert_eq!(
+            CacheWrite::acquire_vary(&headers),
+            vec!["accept".to_string(), "accept-language".to_string()]
+        );
+
+        headers.set_raw("Vary", "*");
+
+        assert!(CacheWrite::acquire_vary(&headers).is_empty());
+    }
 
     #[test]
     #[should_panic]
     fn it_fails_saving_cache() {
         assert!(CacheWrite::save(
-            "bloom:0:c:90d52bc6:f773d6f1".to_string(),
-            "90d52bc6:f773d6f1".to_string(),
-            "90d52bc6".to_string(),
-            0,
-            Method::Get,
-            HttpVersion::Http11,
+            CacheContext {
+                shard: 0,
+                auth_hash: "90d52bc6".to_string(),
+                key: "bloom:0:c:90d52bc6:f773d6f1".to_string(),
+                key_mask: "90d52bc6:f773d6f1".to_string(),
+                method: Method::Get,
+                uri: "/".parse().unwrap(),
+                headers: Headers::new(),
+                rule: None,
+            },
             StatusCode::Ok,
             Headers::new(),
             Body::empty(),
@@ -4,7 +4,9 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
-use std::net::SocketAddr;
+use regex::Regex;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 
 use super::defaults;
 use super::env_var;
@@ -15,7 +17,12 @@ pub struct Config {
     pub control: ConfigControl,
     pub proxy: ConfigProxy,
     pub cache: ConfigCache,
+
+    #[serde(default)]
     pub redis: ConfigRedis,
+
+    #[serde(default)]
+    pub memory: ConfigMemory,
 }
 
 #[derive(Deserialize)]
@@ -50,9 +57,33 @@ pub struct ConfigProxy {
     #[serde(default = "defaults::proxy_shard_default")]
     pub shard_default: u8,
 
+    #[serde(default = "defaults::proxy_tunnel_timeout")]
+    pub tunnel_timeout: u64,
+
+    #[serde(default = "defaults::proxy_auth_source")]
+    pub auth_source: ConfigProxyAuthSource,
+
+    #[serde(default, deserialize_with = "env_var::opt_str")]
+    pub auth_name: Option<String>,
+
+    #[serde(default, deserialize_with = "env_var::opt_str")]
+    pub auth_jwt_secret: Option<String>,
+
+    #[serde(default)]
+    pub purge_allow: Vec<IpAddr>,
+
     pub shard: Vec<ConfigProxyShard>,
 }
 
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigProxyAuthSource {
+    Authorization,
+    Header,
+    Cookie,
+    Jwt,
+}
+
 #[derive(Deserialize)]
 pub struct ConfigProxyShard {
     #[serde(default = "defaults::proxy_shard_shard")]
@@ -70,9 +101,60 @@ pub struct ConfigProxyShard {
 
 #[derive(Deserialize)]
 pub struct ConfigCache {
+    #[serde(default = "defaults::cache_backend")]
+    pub backend: ConfigCacheBackend,
+
     #[serde(default = "defaults::cache_ttl_default")]
     pub ttl_default: usize,
 
+    #[serde(default)]
+    pub ttl_status: HashMap<String, usize>,
+
+    #[serde(default = "defaults::cache_statuses")]
+    pub statuses: Vec<u16>,
+
+    #[serde(
+        default = "defaults::cache_honor_http_caching",
+        deserialize_with = "env_var::bool"
+    )]
+    pub honor_http_caching: bool,
+
+    #[serde(
+        default = "defaults::cache_honor_cdn_headers",
+        deserialize_with = "env_var::bool"
+    )]
+    pub honor_cdn_headers: bool,
+
+    #[serde(default, deserialize_with = "env_var::opt_str")]
+    pub bucket_separator: Option<String>,
+
+    #[serde(
+        default = "defaults::cache_purge_entries",
+        deserialize_with = "env_var::bool"
+    )]
+    pub purge_entries: bool,
+
+    #[serde(default = "defaults::cache_stale_while_revalidate")]
+    pub stale_while_revalidate: usize,
+
+    #[serde(default = "defaults::cache_stale_if_error")]
+    pub stale_if_error: usize,
+
+    #[serde(
+        default = "defaults::cache_coalesce",
+        deserialize_with = "env_var::bool"
+    )]
+    pub coalesce: bool,
+
+    #[serde(
+        default = "defaults::cache_coalesce_lock",
+        deserialize_with = "env_var::bool"
+    )]
+    pub coalesce_lock: bool,
+
+    #[serde(default = "defaults::cache_coalesce_lock_timeout")]
+    pub coalesce_lock_timeout: u64,
+
     #[serde(default = "defaults::cache_executor_pool")]
     pub executor_pool: u16,
 
@@ -93,19 +175,174 @@ pub struct ConfigCache {
         deserialize_with = "env_var::bool"
     )]
     pub compress_body: bool,
+
+    #[serde(default = "defaults::cache_local_max_size")]
+    pub local_max_size: usize,
+
+    #[serde(default = "defaults::cache_local_ttl")]
+    pub local_ttl: usize,
+
+    #[serde(default)]
+    pub key: Vec<ConfigCacheKey>,
+
+    #[serde(default)]
+    pub rule: Vec<ConfigCacheRule>,
+
+    #[serde(default)]
+    pub invalidate: Vec<ConfigCacheInvalidate>,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigCacheKey {
+    #[serde(deserialize_with = "env_var::regex")]
+    pub path: Regex,
+
+    #[serde(default)]
+    pub query_include: Vec<String>,
+
+    #[serde(default)]
+    pub query_exclude: Vec<String>,
+
+    #[serde(
+        default = "defaults::cache_key_query_sort",
+        deserialize_with = "env_var::bool"
+    )]
+    pub query_sort: bool,
+
+    #[serde(
+        default = "defaults::cache_key_path_case_fold",
+        deserialize_with = "env_var::bool"
+    )]
+    pub path_case_fold: bool,
+
+    #[serde(
+        default = "defaults::cache_key_ignore_version",
+        deserialize_with = "env_var::bool"
+    )]
+    pub ignore_version: bool,
+
+    #[serde(
+        default = "defaults::cache_key_ignore_origin",
+        deserialize_with = "env_var::bool"
+    )]
+    pub ignore_origin: bool,
+
+    #[serde(default)]
+    pub headers: Vec<String>,
+
+    #[serde(default)]
+    pub cookies: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigCacheRule {
+    #[serde(default)]
+    pub methods: Vec<String>,
+
+    #[serde(deserialize_with = "env_var::regex")]
+    pub path: Regex,
+
+    #[serde(default, deserialize_with = "env_var::opt_str")]
+    pub host: Option<String>,
+
+    pub ttl: Option<usize>,
+
+    #[serde(default)]
+    pub ttl_status: HashMap<String, usize>,
+
+    #[serde(
+        default = "defaults::cache_rule_bypass",
+        deserialize_with = "env_var::bool"
+    )]
+    pub bypass: bool,
+
+    #[serde(default)]
+    pub buckets: Vec<String>,
+
+    pub max_body_size: Option<usize>,
+
+    #[serde(
+        default = "defaults::cache_rule_post",
+        deserialize_with = "env_var::bool"
+    )]
+    pub post: bool,
+
+    #[serde(
+        default = "defaults::cache_rule_post_canonical_json",
+        deserialize_with = "env_var::bool"
+    )]
+    pub post_canonical_json: bool,
+
+    #[serde(default = "defaults::cache_rule_post_max_body_size")]
+    pub post_max_body_size: u64,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigCacheInvalidate {
+    #[serde(default)]
+    pub methods: Vec<String>,
+
+    #[serde(deserialize_with = "env_var::regex")]
+    pub path: Regex,
+
+    #[serde(default)]
+    pub purge: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigCacheBackend {
+    Redis,
+    Memory,
 }
 
 #[derive(Deserialize)]
 pub struct ConfigRedis {
+    #[serde(default = "defaults::redis_mode")]
+    pub mode: ConfigRedisMode,
+
     #[serde(default = "defaults::redis_host", deserialize_with = "env_var::str")]
     pub host: String,
 
     #[serde(default = "defaults::redis_port")]
     pub port: u16,
 
+    #[serde(default)]
+    pub replica_nodes: Vec<String>,
+
+    #[serde(default)]
+    pub cluster_nodes: Vec<String>,
+
+    #[serde(default = "defaults::redis_sentinel_master_name")]
+    pub sentinel_master_name: String,
+
+    #[serde(default)]
+    pub sentinel_nodes: Vec<String>,
+
+    #[serde(default = "defaults::redis_sentinel_check_interval_seconds")]
+    pub sentinel_check_interval_seconds: u64,
+
+    #[serde(default, deserialize_with = "env_var::opt_str")]
+    pub socket_path: Option<String>,
+
+    #[serde(default, deserialize_with = "env_var::opt_str")]
+    pub username: Option<String>,
+
     #[serde(default, deserialize_with = "env_var::opt_str")]
     pub password: Option<String>,
 
+    #[serde(default = "defaults::redis_tls", deserialize_with = "env_var::bool")]
+    pub tls: bool,
+
+    #[serde(default, deserialize_with = "env_var::opt_str")]
+    pub tls_ca_path: Option<String>,
+
+    #[serde(default, deserialize_with = "env_var::opt_str")]
+    pub tls_client_cert_path: Option<String>,
+
+    #[serde(default, deserialize_with = "env_var::opt_str")]
+    pub tls_client_key_path: Option<String>,
+
     #[serde(default = "defaults::redis_database")]
     pub database: u8,
 
@@ -127,3 +364,54 @@ pub struct ConfigRedis {
     #[serde(default = "defaults::redis_max_key_expiration")]
     pub max_key_expiration: usize,
 }
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigRedisMode {
+    Standalone,
+    Cluster,
+    Sentinel,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigMemory {
+    #[serde(default = "defaults::memory_max_size")]
+    pub max_size: usize,
+}
+
+impl Default for ConfigRedis {
+    fn default() -> Self {
+        Self {
+            mode: defaults::redis_mode(),
+            host: defaults::redis_host(),
+            port: defaults::redis_port(),
+            replica_nodes: Vec::new(),
+            cluster_nodes: Vec::new(),
+            sentinel_master_name: defaults::redis_sentinel_master_name(),
+            sentinel_nodes: Vec::new(),
+            sentinel_check_interval_seconds: defaults::redis_sentinel_check_interval_seconds(),
+            socket_path: None,
+            username: None,
+            password: None,
+            tls: defaults::redis_tls(),
+            tls_ca_path: None,
+            tls_client_cert_path: None,
+            tls_client_key_path: None,
+            database: defaults::redis_database(),
+            pool_size: defaults::redis_pool_size(),
+            max_lifetime_seconds: defaults::redis_max_lifetime_seconds(),
+            idle_timeout_seconds: defaults::redis_idle_timeout_seconds(),
+            connection_timeout_seconds: defaults::redis_connection_timeout_seconds(),
+            max_key_size: defaults::redis_max_key_size(),
+            max_key_expiration: defaults::redis_max_key_expiration(),
+        }
+    }
+}
+
+impl Default for ConfigMemory {
+    fn default() -> Self {
+        Self {
+            max_size: defaults::memory_max_size(),
+        }
+    }
+}
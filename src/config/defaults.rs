@@ -6,6 +6,8 @@
 
 use std::net::SocketAddr;
 
+use super::config::{ConfigCacheBackend, ConfigProxyAuthSource, ConfigRedisMode};
+
 pub fn server_log_level() -> String {
     "error".to_string()
 }
@@ -26,6 +28,14 @@ pub const fn proxy_shard_default() -> u8 {
     0
 }
 
+pub const fn proxy_tunnel_timeout() -> u64 {
+    0
+}
+
+pub const fn proxy_auth_source() -> ConfigProxyAuthSource {
+    ConfigProxyAuthSource::Authorization
+}
+
 pub const fn proxy_shard_shard() -> u8 {
     0
 }
@@ -38,6 +48,49 @@ pub const fn proxy_shard_port() -> u16 {
     3000
 }
 
+pub const fn cache_backend() -> ConfigCacheBackend {
+    ConfigCacheBackend::Redis
+}
+
+pub fn cache_statuses() -> Vec<u16> {
+    vec![
+        200, 203, 204, 205, 206, 207, 208, 300, 301, 302, 303, 308, 401, 402, 403, 404, 405, 410,
+        414, 415, 416, 417, 418, 423, 424, 428, 431, 501, 510,
+    ]
+}
+
+pub const fn cache_honor_http_caching() -> bool {
+    false
+}
+
+pub const fn cache_honor_cdn_headers() -> bool {
+    false
+}
+
+pub const fn cache_purge_entries() -> bool {
+    false
+}
+
+pub const fn cache_stale_while_revalidate() -> usize {
+    0
+}
+
+pub const fn cache_stale_if_error() -> usize {
+    0
+}
+
+pub const fn cache_coalesce() -> bool {
+    false
+}
+
+pub const fn cache_coalesce_lock() -> bool {
+    false
+}
+
+pub const fn cache_coalesce_lock_timeout() -> u64 {
+    10
+}
+
 pub const fn cache_ttl_default() -> usize {
     600
 }
@@ -57,6 +110,51 @@ pub const fn cache_disable_write() -> bool {
 pub const fn cache_compress_body() -> bool {
     true
 }
+
+pub const fn cache_local_max_size() -> usize {
+    0
+}
+
+pub const fn cache_local_ttl() -> usize {
+    10
+}
+
+pub const fn cache_key_query_sort() -> bool {
+    false
+}
+
+pub const fn cache_key_path_case_fold() -> bool {
+    false
+}
+
+pub const fn cache_key_ignore_version() -> bool {
+    false
+}
+
+pub const fn cache_key_ignore_origin() -> bool {
+    false
+}
+
+pub const fn cache_rule_bypass() -> bool {
+    false
+}
+
+pub const fn cache_rule_post() -> bool {
+    false
+}
+
+pub const fn cache_rule_post_canonical_json() -> bool {
+    false
+}
+
+pub const fn cache_rule_post_max_body_size() -> u64 {
+    1048576
+}
+
+pub const fn redis_mode() -> ConfigRedisMode {
+    ConfigRedisMode::Standalone
+}
+
 pub fn redis_host() -> String {
     "localhost".to_string()
 }
@@ -65,6 +163,18 @@ pub const fn redis_port() -> u16 {
     6379
 }
 
+pub fn redis_sentinel_master_name() -> String {
+    "mymaster".to_string()
+}
+
+pub const fn redis_sentinel_check_interval_seconds() -> u64 {
+    1
+}
+
+pub const fn redis_tls() -> bool {
+    false
+}
+
 pub const fn redis_database() -> u8 {
     0
 }
@@ -92,3 +202,7 @@ pub const fn redis_max_key_size() -> usize {
 pub const fn redis_max_key_expiration() -> usize {
     2_592_000
 }
+
+pub const fn memory_max_size() -> usize {
+    67_108_864
+}
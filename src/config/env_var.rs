@@ -55,6 +55,15 @@ where
     }
 }
 
+pub fn regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = str(deserializer)?;
+
+    Regex::new(&value).map_err(de::Error::custom)
+}
+
 pub fn bool<'de, D>(deserializer: D) -> Result<bool, D::Error>
 where
     D: Deserializer<'de>,
@@ -7,6 +7,7 @@
 mod defaults;
 mod env_var;
 
+#[allow(clippy::module_inception)]
 pub mod config;
 pub mod logger;
 pub mod reader;
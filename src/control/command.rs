@@ -4,12 +4,14 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
+use hyper::Method;
 use std::str::SplitWhitespace;
 
 use super::handle::ControlShard;
 use crate::cache::route::CacheRoute;
 use crate::cache::store::CachePurgeVariant;
-use crate::APP_CACHE_STORE;
+use crate::proxy::defaults;
+use crate::{APP_CACHE_STORE, APP_CONF};
 
 #[derive(PartialEq, Eq)]
 pub enum ControlCommandResponse {
@@ -41,19 +43,11 @@ impl ControlCommandResponse {
 }
 
 impl ControlCommand {
-    pub fn dispatch_flush_bucket(
-        shard: &ControlShard,
-        mut parts: SplitWhitespace,
-    ) -> ControlResult {
-        let bucket = parts.next().unwrap_or("");
-
-        if !bucket.is_empty() {
-            let (bucket_key, _) = CacheRoute::gen_key_bucket_from_hash(*shard, bucket);
-
-            return Self::proceed_flush(CachePurgeVariant::Bucket, shard, &bucket_key);
+    pub fn dispatch_flush_bucket(shard: &ControlShard, parts: SplitWhitespace) -> ControlResult {
+        match Self::parse_flush_bucket(shard, parts) {
+            Some((variant, bucket_key)) => Self::proceed_flush(variant, shard, &bucket_key),
+            None => Err(None),
         }
-
-        Err(None)
     }
 
     pub fn dispatch_flush_auth(shard: &ControlShard, mut parts: SplitWhitespace) -> ControlResult {
@@ -68,6 +62,18 @@ impl ControlCommand {
         Err(None)
     }
 
+    pub fn dispatch_flush_key(shard: &ControlShard, parts: SplitWhitespace) -> ControlResult {
+        // Routes are only tagged with their entry if enabled, thus there would be nothing to flush
+        if !APP_CONF.cache.purge_entries {
+            return Err(None);
+        }
+
+        match Self::parse_flush_key(shard, parts) {
+            Some(key_entry) => Self::proceed_flush(CachePurgeVariant::Key, shard, &key_entry),
+            None => Err(None),
+        }
+    }
+
     pub const fn dispatch_ping() -> ControlResult {
         Ok(ControlCommandResponse::Pong)
     }
@@ -110,6 +116,50 @@ impl ControlCommand {
             }
         }
     }
+
+    fn parse_flush_bucket(
+        shard: &ControlShard,
+        mut parts: SplitWhitespace,
+    ) -> Option<(CachePurgeVariant, String)> {
+        let bucket = parts.next().unwrap_or("");
+
+        // Soft flush? Mark bucket cache as stale instead (eg. 'FLUSHB <bucket> SOFT')
+        let variant = match parts.next() {
+            None => CachePurgeVariant::Bucket,
+            Some("SOFT") => CachePurgeVariant::BucketSoft,
+            Some(_) => return None,
+        };
+
+        if !bucket.is_empty() && parts.next().is_none() {
+            let (bucket_key, _) = CacheRoute::gen_key_bucket_from_hash(*shard, bucket);
+
+            return Some((variant, bucket_key));
+        }
+
+        None
+    }
+
+    fn parse_flush_key(shard: &ControlShard, mut parts: SplitWhitespace) -> Option<String> {
+        let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+        // Flush key for anonymous requests, if no authorization is given
+        let auth = parts.next().map_or_else(
+            || CacheRoute::hash(defaults::REQUEST_AUTHORIZATION_DEFAULT),
+            str::to_string,
+        );
+
+        if let (Ok(method), true) = (method.parse::<Method>(), path.starts_with('/')) {
+            // Query is passed along with the path (eg. '/users?page=2')
+            let (path, query) = match path.split_once('?') {
+                Some((path, query)) => (path, Some(query)),
+                None => (path, None),
+            };
+
+            return Some(CacheRoute::gen_key_entry(*shard, &auth, &method, path, query).0);
+        }
+
+        None
+    }
 }
 
 #[cfg(test)]
@@ -124,4 +174,50 @@ mod tests {
         assert_eq!(ControlCommandResponse::Ended.to_str(), "ENDED quit");
         assert_eq!(ControlCommandResponse::Err.to_str(), "ERR");
     }
+
+    #[test]
+    fn it_parses_flush_bucket() {
+        let parse = |command: &str| {
+            ControlCommand::parse_flush_bucket(&0, command.split_whitespace())
+                .map(|(variant, bucket_key)| (variant.is_soft(), bucket_key))
+        };
+
+        let bucket_key = CacheRoute::gen_key_bucket_from_hash(0, "2eb6c00c").0;
+
+        assert_eq!(parse("2eb6c00c"), Some((false, bucket_key.to_owned())));
+        assert_eq!(parse("2eb6c00c SOFT"), Some((true, bucket_key)));
+        assert_eq!(parse("2eb6c00c HARD"), None);
+        assert_eq!(parse("2eb6c00c SOFT SOFT"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn it_parses_flush_key() {
+        let parse = |command: &str| ControlCommand::parse_flush_key(&0, command.split_whitespace());
+
+        let anonymous = CacheRoute::hash(defaults::REQUEST_AUTHORIZATION_DEFAULT);
+
+        assert_eq!(
+            parse("GET /users?page=2"),
+            Some(
+                CacheRoute::gen_key_entry(0, &anonymous, &Method::Get, "/users", Some("page=2")).0
+            )
+        );
+        assert_eq!(
+            parse("HEAD /users/42 b44c6f8e"),
+            Some(CacheRoute::gen_key_entry(0, "b44c6f8e", &Method::Head, "/users/42", None).0)
+        );
+        assert_eq!(parse("GET"), None);
+        assert_eq!(parse("GET users"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn it_rejects_flush_key_if_entries_not_tagged() {
+        assert!(!APP_CONF.cache.purge_entries);
+        assert!(matches!(
+            ControlCommand::dispatch_flush_key(&0, "GET /users".split_whitespace()),
+            Err(None)
+        ));
+    }
 }
@@ -267,6 +267,7 @@ impl ControlHandle {
             "" => Ok(ControlCommandResponse::Void),
             "FLUSHB" => ControlCommand::dispatch_flush_bucket(shard, parts),
             "FLUSHA" => ControlCommand::dispatch_flush_auth(shard, parts),
+            "FLUSHK" => ControlCommand::dispatch_flush_key(shard, parts),
             "PING" => ControlCommand::dispatch_ping(),
             "SHARD" => ControlCommand::dispatch_shard(shard, parts),
             "QUIT" => ControlCommand::dispatch_quit(),
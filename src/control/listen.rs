@@ -15,6 +15,7 @@ pub struct ControlListenBuilder;
 pub struct ControlListen;
 
 impl ControlListenBuilder {
+    #[allow(clippy::new_ret_no_self)]
     pub const fn new() -> ControlListen {
         ControlListen {}
     }
@@ -9,6 +9,7 @@ use hyper::{header, Headers};
 
 use super::response_buckets::HeaderResponseBloomResponseBuckets;
 use super::response_ignore::HeaderResponseBloomResponseIgnore;
+use super::response_purge_buckets::HeaderResponseBloomResponsePurgeBuckets;
 use super::response_ttl::HeaderResponseBloomResponseTTL;
 
 pub struct HeaderJanitor;
@@ -41,6 +42,7 @@ impl HeaderJanitor {
     pub fn is_internal(header: &HeaderView) -> bool {
         header.is::<HeaderResponseBloomResponseBuckets>()
             || header.is::<HeaderResponseBloomResponseIgnore>()
+            || header.is::<HeaderResponseBloomResponsePurgeBuckets>()
             || header.is::<HeaderResponseBloomResponseTTL>()
     }
 }
@@ -13,6 +13,8 @@ use std::str;
 pub enum HeaderBloomStatusValue {
     Hit,
     Miss,
+    Stale,
+    Coalesced,
     Direct,
     Reject,
     Offline,
@@ -26,6 +28,8 @@ impl HeaderBloomStatusValue {
         match *self {
             Self::Hit => "HIT",
             Self::Miss => "MISS",
+            Self::Stale => "STALE",
+            Self::Coalesced => "COALESCED",
             Self::Direct => "DIRECT",
             Self::Reject => "REJECT",
             Self::Offline => "OFFLINE",
@@ -43,6 +47,8 @@ impl Header for HeaderBloomStatus {
             Some(header_raw) => match str::from_utf8(header_raw) {
                 Ok("HIT") => Ok(Self(HeaderBloomStatusValue::Hit)),
                 Ok("MISS") => Ok(Self(HeaderBloomStatusValue::Miss)),
+                Ok("STALE") => Ok(Self(HeaderBloomStatusValue::Stale)),
+                Ok("COALESCED") => Ok(Self(HeaderBloomStatusValue::Coalesced)),
                 Ok("DIRECT") => Ok(Self(HeaderBloomStatusValue::Direct)),
                 Ok("REJECT") => Ok(Self(HeaderBloomStatusValue::Reject)),
                 Ok("OFFLINE") => Ok(Self(HeaderBloomStatusValue::Offline)),
@@ -71,6 +77,8 @@ mod tests {
     fn it_matches_status_string() {
         assert_eq!(HeaderBloomStatusValue::Hit.to_str(), "HIT");
         assert_eq!(HeaderBloomStatusValue::Miss.to_str(), "MISS");
+        assert_eq!(HeaderBloomStatusValue::Stale.to_str(), "STALE");
+        assert_eq!(HeaderBloomStatusValue::Coalesced.to_str(), "COALESCED");
         assert_eq!(HeaderBloomStatusValue::Direct.to_str(), "DIRECT");
         assert_eq!(HeaderBloomStatusValue::Reject.to_str(), "REJECT");
         assert_eq!(HeaderBloomStatusValue::Offline.to_str(), "OFFLINE");
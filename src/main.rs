@@ -12,15 +12,19 @@ extern crate clap;
 extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
+extern crate base64;
 extern crate brotli;
 extern crate farmhash;
 extern crate futures;
 extern crate futures_cpupool;
+extern crate hmac;
 extern crate httparse;
 extern crate hyper;
 extern crate rand;
 extern crate redis;
 extern crate regex;
+extern crate serde_json;
+extern crate sha2;
 extern crate tokio_core;
 extern crate toml;
 extern crate unicase;
@@ -55,6 +59,8 @@ pub static LINE_FEED: &str = "\r\n";
 pub static THREAD_NAME_WORKER: &str = "bloom-worker";
 pub static THREAD_NAME_CONTROL_MASTER: &str = "bloom-control-master";
 pub static THREAD_NAME_CONTROL_CLIENT: &str = "bloom-control-client";
+pub static THREAD_NAME_CACHE_INVALIDATION: &str = "bloom-cache-invalidation";
+pub static THREAD_NAME_CACHE_SENTINEL: &str = "bloom-cache-sentinel";
 
 lazy_static! {
     static ref APP_ARGS: AppArgs = make_app_args();
@@ -0,0 +1,300 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use hyper::header::Cookie;
+use hyper::Headers;
+use serde_json::Value;
+use sha2::{Sha256, Sha384, Sha512};
+use std::str::from_utf8;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::defaults;
+use crate::config::config::ConfigProxyAuthSource;
+use crate::APP_CONF;
+
+pub struct ProxyAuth;
+
+impl ProxyAuth {
+    pub fn identify(headers: &Headers) -> String {
+        Self::identify_from(
+            headers,
+            &APP_CONF.proxy.auth_source,
+            APP_CONF.proxy.auth_name.as_deref(),
+            APP_CONF.proxy.auth_jwt_secret.as_deref(),
+            Self::now(),
+        )
+    }
+
+    fn identify_from(
+        headers: &Headers,
+        source: &ConfigProxyAuthSource,
+        name: Option<&str>,
+        jwt_secret: Option<&str>,
+        now: u64,
+    ) -> String {
+        let authorization = Self::read_header(headers, "authorization");
+
+        let identity = match *source {
+            // Notice: the raw 'Authorization' header is used as-is there, so that cache keys \
+            //   do not change from the ones of Bloom versions without identity sources.
+            ConfigProxyAuthSource::Authorization => {
+                return authorization
+                    .unwrap_or(defaults::REQUEST_AUTHORIZATION_DEFAULT)
+                    .to_string();
+            }
+            ConfigProxyAuthSource::Header => name
+                .and_then(|header_name| Self::read_header(headers, header_name))
+                .map(|value| ("header", value.to_string())),
+            ConfigProxyAuthSource::Cookie => name.and_then(|name| {
+                headers
+                    .get::<Cookie>()
+                    .and_then(|cookie| cookie.get(name))
+                    .map(|value| ("cookie", value.to_string()))
+            }),
+            ConfigProxyAuthSource::Jwt => match (authorization, jwt_secret) {
+                (Some(authorization), Some(secret)) => Self::read_jwt_claim(
+                    Self::strip_bearer(authorization),
+                    secret.as_bytes(),
+                    name.unwrap_or(defaults::REQUEST_AUTH_JWT_CLAIM_DEFAULT),
+                    now,
+                )
+                .map(|claim| ("jwt", claim)),
+                _ => None,
+            },
+        };
+
+        // Notice: fallback on the raw 'Authorization' header if the identity cannot be \
+        //   derived from the configured source (eg. invalid or expired JWT), so that \
+        //   authenticated requests never end up sharing the anonymous cache. Identities are \
+        //   namespaced by where they come from, as a raw 'Authorization' value (eg. '42') \
+        //   must never share the cache of a trusted identity (eg. a signed JWT with 'sub=42').
+        match (
+            identity.filter(|(_, value)| !value.is_empty()),
+            authorization,
+        ) {
+            (Some((namespace, value)), _) => format!("{namespace}:{value}"),
+            (None, Some(authorization)) => format!("authorization:{authorization}"),
+            (None, None) => defaults::REQUEST_AUTHORIZATION_DEFAULT.to_string(),
+        }
+    }
+
+    fn read_header<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+        headers
+            .get_raw(name)
+            .and_then(|value| value.one())
+            .and_then(|value| from_utf8(value).ok())
+    }
+
+    fn strip_bearer(authorization: &str) -> &str {
+        match authorization.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => token.trim(),
+            _ => authorization,
+        }
+    }
+
+    fn read_jwt_claim(token: &str, secret: &[u8], claim: &str, now: u64) -> Option<String> {
+        let mut parts = token.split('.');
+
+        let (header, payload, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(payload), Some(signature)) if parts.next().is_none() => {
+                (header, payload, signature)
+            }
+            _ => return None,
+        };
+
+        // Only trust tokens signed with the shared secret, as the claim could otherwise be \
+        //   forged by anyone to read the cache of another user.
+        let signed = &token[..(header.len() + payload.len() + 1)];
+
+        let header: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+
+        let is_valid = match header.get("alg").and_then(Value::as_str) {
+            Some("HS256") => Self::verify_hmac::<Hmac<Sha256>>(secret, signed, &signature),
+            Some("HS384") => Self::verify_hmac::<Hmac<Sha384>>(secret, signed, &signature),
+            Some("HS512") => Self::verify_hmac::<Hmac<Sha512>>(secret, signed, &signature),
+            _ => false,
+        };
+
+        if !is_valid {
+            return None;
+        }
+
+        let payload: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+
+        // Expired or not yet valid tokens must go through the API, which will reject them
+        if payload
+            .get("exp")
+            .and_then(Value::as_u64)
+            .is_some_and(|exp| exp <= now)
+            || payload
+                .get("nbf")
+                .and_then(Value::as_u64)
+                .is_some_and(|nbf| nbf > now)
+        {
+            return None;
+        }
+
+        match payload.get(claim)? {
+            Value::String(value) => Some(value.to_owned()),
+            Value::Number(value) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+
+    fn verify_hmac<M: Mac + hmac::digest::KeyInit>(
+        secret: &[u8],
+        signed: &str,
+        signature: &[u8],
+    ) -> bool {
+        match <M as Mac>::new_from_slice(secret) {
+            Ok(mut mac) => {
+                mac.update(signed.as_bytes());
+
+                mac.verify_slice(signature).is_ok()
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sign_jwt(secret: &[u8], payload: &str) -> String {
+        let signed = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"HS256","typ":"JWT"}"#),
+            URL_SAFE_NO_PAD.encode(payload)
+        );
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+
+        mac.update(signed.as_bytes());
+
+        format!(
+            "{}.{}",
+            signed,
+            URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+        )
+    }
+
+    #[test]
+    fn it_reads_jwt_claim() {
+        let token = sign_jwt(b"secret", r#"{"sub":"user-1","uid":42,"exp":2000}"#);
+
+        assert_eq!(
+            ProxyAuth::read_jwt_claim(&token, b"secret", "sub", 1000),
+            Some("user-1".to_string())
+        );
+        assert_eq!(
+            ProxyAuth::read_jwt_claim(&token, b"secret", "uid", 1000),
+            Some("42".to_string())
+        );
+        assert_eq!(
+            ProxyAuth::read_jwt_claim(&token, b"secret", "name", 1000),
+            None
+        );
+    }
+
+    #[test]
+    fn it_rejects_untrusted_jwt() {
+        let token = sign_jwt(b"secret", r#"{"sub":"user-1","exp":2000}"#);
+        let token_other = sign_jwt(b"secret", r#"{"sub":"user-2","exp":2000}"#);
+
+        let token_tampered = format!(
+            "{}.{}",
+            token_other.rsplit_once('.').unwrap().0,
+            token.rsplit_once('.').unwrap().1
+        );
+
+        assert_eq!(
+            ProxyAuth::read_jwt_claim(&token, b"other", "sub", 1000),
+            None
+        );
+        assert_eq!(
+            ProxyAuth::read_jwt_claim(&token, b"secret", "sub", 2000),
+            None
+        );
+        assert_eq!(
+            ProxyAuth::read_jwt_claim(&token_tampered, b"secret", "sub", 1000),
+            None
+        );
+        assert_eq!(
+            ProxyAuth::read_jwt_claim("user-1", b"secret", "sub", 1000),
+            None
+        );
+    }
+
+    #[test]
+    fn it_strips_bearer_scheme() {
+        assert_eq!(ProxyAuth::strip_bearer("Bearer abc.def.ghi"), "abc.def.ghi");
+        assert_eq!(ProxyAuth::strip_bearer("bearer abc.def.ghi"), "abc.def.ghi");
+        assert_eq!(ProxyAuth::strip_bearer("abc.def.ghi"), "abc.def.ghi");
+    }
+
+    #[test]
+    fn it_namespaces_identities() {
+        let token = sign_jwt(b"secret", r#"{"sub":"42"}"#);
+
+        let identify = |source, name, authorization: &str| {
+            let mut headers = Headers::new();
+
+            headers.set_raw("Authorization", authorization.to_string());
+            headers.set_raw("X-User", "42");
+
+            ProxyAuth::identify_from(&headers, &source, name, Some("secret"), 1000)
+        };
+
+        let identity_signed = identify(ConfigProxyAuthSource::Jwt, None, &token);
+
+        assert_eq!(identity_signed, "jwt:42");
+        assert_ne!(
+            identify(ConfigProxyAuthSource::Jwt, None, "42"),
+            identity_signed
+        );
+        assert_ne!(
+            identify(ConfigProxyAuthSource::Jwt, None, "jwt:42"),
+            identity_signed
+        );
+        assert_eq!(
+            identify(ConfigProxyAuthSource::Jwt, None, "42"),
+            "authorization:42"
+        );
+        assert_eq!(
+            identify(ConfigProxyAuthSource::Header, Some("X-User"), "42"),
+            "header:42"
+        );
+        assert_eq!(
+            identify(ConfigProxyAuthSource::Header, Some("X-Other"), "header:42"),
+            "authorization:header:42"
+        );
+        assert_eq!(
+            identify(ConfigProxyAuthSource::Authorization, None, "42"),
+            "42"
+        );
+        assert_eq!(
+            ProxyAuth::identify_from(
+                &Headers::new(),
+                &ConfigProxyAuthSource::Jwt,
+                None,
+                Some("secret"),
+                1000
+            ),
+            defaults::REQUEST_AUTHORIZATION_DEFAULT
+        );
+    }
+}
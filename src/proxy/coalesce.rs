@@ -0,0 +1,170 @@
+// Bloom
+//
+// HTTP REST API caching middleware
+// Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
+// License: Mozilla Public License v2.0 (MPL v2.0)
+
+use futures::future::{self, Future, Loop};
+use futures::sync::oneshot;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio_core::reactor::Timeout;
+
+use crate::cache::route::CacheRoute;
+use crate::server::listen::LISTEN_HANDLE;
+use crate::{APP_CACHE_STORE, APP_CONF};
+
+const LOCK_POLL_INTERVAL_MILLISECONDS: u64 = 50;
+
+lazy_static! {
+    static ref COALESCE_REGISTER: Mutex<HashMap<String, Vec<oneshot::Sender<bool>>>> =
+        Mutex::new(HashMap::new());
+}
+
+pub struct ProxyCoalesce;
+
+pub enum ProxyCoalesceRole {
+    Leader(ProxyCoalesceLeader),
+    Follower { leader_failed: bool },
+}
+
+pub struct ProxyCoalesceLeader {
+    ns: String,
+    lock: Option<(String, String)>,
+    failed: bool,
+}
+
+type ProxyCoalesceWaitFuture = Box<dyn Future<Item = (), Error = ()>>;
+
+pub type ProxyCoalesceFuture = Box<dyn Future<Item = ProxyCoalesceRole, Error = ()>>;
+
+impl ProxyCoalesce {
+    pub fn join(shard: u8, ns: &str, ns_mask: &str) -> ProxyCoalesceFuture {
+        {
+            let mut register = COALESCE_REGISTER
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+
+            // Already fetching this key from this Bloom instance? Wait for its response
+            if let Some(followers) = register.get_mut(ns) {
+                let (sender, receiver) = oneshot::channel();
+
+                followers.push(sender);
+
+                debug!("coalescing request on ns = {}", ns);
+
+                return Box::new(receiver.then(|leader_failed| {
+                    Ok(ProxyCoalesceRole::Follower {
+                        leader_failed: leader_failed.unwrap_or(false),
+                    })
+                }));
+            }
+
+            register.insert(ns.to_string(), Vec::new());
+        }
+
+        let mut leader = ProxyCoalesceLeader {
+            ns: ns.to_string(),
+            lock: None,
+            failed: false,
+        };
+
+        if !APP_CONF.cache.coalesce_lock {
+            return Box::new(future::ok(ProxyCoalesceRole::Leader(leader)));
+        }
+
+        // Also lock the key out from other Bloom instances, for the time of the fetch
+        let lock_key = CacheRoute::gen_key_lock_from_mask(shard, ns_mask);
+        let lock_token = format!("{:x}", rand::random::<u64>());
+
+        Box::new(
+            APP_CACHE_STORE
+                .acquire_lock(lock_key.to_owned(), lock_token.to_owned())
+                .then(move |result| -> ProxyCoalesceFuture {
+                    match result {
+                        Ok(true) => {
+                            leader.lock = Some((lock_key, lock_token));
+
+                            Box::new(future::ok(ProxyCoalesceRole::Leader(leader)))
+                        }
+                        Ok(false) => {
+                            debug!("coalescing request on locked ns = {}", leader.ns);
+
+                            // Hold followers from this Bloom instance until the lock is gone
+                            // Notice: the outcome of a fetch made by another Bloom instance is \
+                            //   unknown, thus it is assumed to have succeeded.
+                            Box::new(Self::wait_lock(lock_key).then(move |_| {
+                                drop(leader);
+
+                                Ok(ProxyCoalesceRole::Follower {
+                                    leader_failed: false,
+                                })
+                            }))
+                        }
+                        Err(err) => {
+                            // Lock failures should not prevent the key from being fetched
+                            warn!("could not acquire lock: {} because: {:?}", lock_key, err);
+
+                            Box::new(future::ok(ProxyCoalesceRole::Leader(leader)))
+                        }
+                    }
+                }),
+        )
+    }
+
+    fn wait_lock(lock_key: String) -> ProxyCoalesceWaitFuture {
+        // Give up waiting once the lock should have expired, as its owner may be gone
+        let deadline = Instant::now() + Duration::from_secs(APP_CONF.cache.coalesce_lock_timeout);
+
+        Box::new(future::loop_fn(lock_key, move |lock_key| {
+            let interval = LISTEN_HANDLE.with(|handle| {
+                Timeout::new(
+                    Duration::from_millis(LOCK_POLL_INTERVAL_MILLISECONDS),
+                    handle,
+                )
+            });
+
+            future::result(interval)
+                .flatten()
+                .map_err(|_| ())
+                .and_then(move |()| {
+                    APP_CACHE_STORE
+                        .has_lock(lock_key.to_owned())
+                        .map_err(|_| ())
+                        .map(move |is_locked| {
+                            if is_locked && Instant::now() < deadline {
+                                Loop::Continue(lock_key)
+                            } else {
+                                Loop::Break(())
+                            }
+                        })
+                })
+        }))
+    }
+}
+
+impl ProxyCoalesceLeader {
+    pub fn fail(&mut self) {
+        // Followers will serve the expired response as well, rather than retrying the API
+        self.failed = true;
+    }
+}
+
+impl Drop for ProxyCoalesceLeader {
+    fn drop(&mut self) {
+        if let Some((lock_key, lock_token)) = self.lock.take() {
+            APP_CACHE_STORE.release_lock(lock_key, lock_token);
+        }
+
+        // Wake up followers, as the response is now known
+        let followers = COALESCE_REGISTER
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.ns);
+
+        for follower in followers.into_iter().flatten() {
+            follower.send(self.failed).ok();
+        }
+    }
+}
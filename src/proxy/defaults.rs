@@ -5,3 +5,4 @@
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
 pub static REQUEST_AUTHORIZATION_DEFAULT: &str = "";
+pub static REQUEST_AUTH_JWT_CLAIM_DEFAULT: &str = "sub";
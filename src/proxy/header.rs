@@ -6,24 +6,17 @@
 
 use hyper::header::{ETag, Header, Vary};
 use hyper::Headers;
-use std::str::from_utf8;
 use unicase::Ascii;
 
-use super::defaults;
+use super::auth::ProxyAuth;
 use crate::{header::request_shard::HeaderRequestBloomRequestShard, APP_CONF};
 
 pub struct ProxyHeader;
 
 impl ProxyHeader {
     pub fn parse_from_request(headers: Headers) -> (Headers, String, u8) {
-        // Request header: 'Authorization'
-        let auth = headers
-            .get_raw("authorization")
-            .map_or(defaults::REQUEST_AUTHORIZATION_DEFAULT, |value| {
-                from_utf8(value.one().unwrap_or(&[]))
-                    .unwrap_or(defaults::REQUEST_AUTHORIZATION_DEFAULT)
-            })
-            .to_string();
+        // Request header: 'Authorization' (or configured auth identity source)
+        let auth = ProxyAuth::identify(&headers);
 
         // Request header: 'Bloom-Request-Shard'
         let shard = headers
@@ -34,9 +27,22 @@ impl ProxyHeader {
     }
 
     pub fn set_etag(headers: &mut Headers, etag: ETag) {
-        headers.set::<Vary>(Vary::Items(vec![Ascii::new(
-            ETag::header_name().to_string(),
-        )]));
+        // Append to the 'Vary' list from the API, as clients must still honor it
+        let vary = match headers.get::<Vary>() {
+            Some(Vary::Any) => None,
+            Some(Vary::Items(header_names)) => Some(header_names.to_owned()),
+            None => Some(Vec::new()),
+        };
+
+        if let Some(mut header_names) = vary {
+            let etag_name = Ascii::new(ETag::header_name().to_string());
+
+            if !header_names.contains(&etag_name) {
+                header_names.push(etag_name);
+            }
+
+            headers.set::<Vary>(Vary::Items(header_names));
+        }
 
         headers.set::<ETag>(etag);
     }
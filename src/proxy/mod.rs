@@ -4,8 +4,10 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
-mod defaults;
+mod auth;
 mod header;
 
+pub mod coalesce;
+pub mod defaults;
 pub mod serve;
 pub mod tunnel;
@@ -5,26 +5,40 @@
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
 use futures::future::{self, Future};
-use httparse;
-use hyper::header::{ETag, EntityTag, IfNoneMatch, Origin};
+use futures::Stream;
+use hyper::header::{ETag, EntityTag, IfModifiedSince, IfNoneMatch};
 use hyper::server::{Request, Response};
-use hyper::{Body, Error, Headers, HttpVersion, Method, StatusCode, Uri};
-use itertools::{Itertools, Position};
+use hyper::{Body, Error, Headers, Method, StatusCode};
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Mutex;
 
+use super::coalesce::{ProxyCoalesce, ProxyCoalesceRole};
 use super::header::ProxyHeader;
 use super::tunnel::ProxyTunnel;
+use crate::cache::check::CacheCheck;
+use crate::cache::context::CacheContext;
+use crate::cache::envelope::CacheEnvelope;
+use crate::cache::invalidate::CacheInvalidate;
 use crate::cache::read::CacheRead;
-use crate::cache::route::CacheRoute;
-use crate::cache::write::CacheWrite;
+use crate::cache::route::{CacheRoute, CacheRouteRequest};
+use crate::cache::store::CacheStoreFreshness;
+use crate::cache::write::{CacheWrite, CacheWriteResultFuture};
 use crate::header::janitor::HeaderJanitor;
+use crate::header::response_purge_buckets::HeaderResponseBloomResponsePurgeBuckets;
 use crate::header::status::{HeaderBloomStatus, HeaderBloomStatusValue};
-use crate::LINE_FEED;
+use crate::server::listen::LISTEN_REMOTE;
+use crate::APP_CONF;
 
 pub struct ProxyServe;
 
-const CACHED_PARSE_MAX_HEADERS: usize = 100;
+lazy_static! {
+    static ref REVALIDATE_REGISTER: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
 
-type ProxyServeResult = Result<(String, Option<String>), ()>;
+// Notice: failed results may hold the namespace and fingerprint of an expired cached response, \
+//   which can still be served if the API fails.
+type ProxyServeResult = Result<(String, Option<Vec<u8>>, bool), Option<(String, String)>>;
 type ProxyServeResultFuture = Box<dyn Future<Item = ProxyServeResult, Error = ()>>;
 
 pub type ProxyServeResponseFuture = Box<dyn Future<Item = Response, Error = Error>>;
@@ -41,10 +55,60 @@ impl ProxyServe {
             | Method::Patch
             | Method::Put
             | Method::Delete => Self::accept(req),
+            Method::Extension(ref method) if method == "PURGE" => Self::purge(req),
             _ => Self::reject(req, StatusCode::MethodNotAllowed),
         }
     }
 
+    fn purge(req: Request) -> ProxyServeResponseFuture {
+        // Notice: the remote address is only provided by this deprecated method in Hyper 0.11, \
+        //   though it remains available for servers built from 'Http::bind()' like Bloom.
+        #[allow(deprecated)]
+        let remote = req.remote_addr().map(|remote| remote.ip());
+
+        if let Err(status) = Self::check_purge(
+            remote,
+            &APP_CONF.proxy.purge_allow,
+            APP_CONF.cache.purge_entries,
+        ) {
+            return Self::reject(req, status);
+        }
+
+        let (method, uri, _, headers, _) = req.deconstruct();
+        let (_, auth, shard) = ProxyHeader::parse_from_request(headers);
+
+        Box::new(
+            CacheInvalidate::from_entry(shard, &CacheRoute::hash(&auth), uri.path(), uri.query())
+                .then(move |result| {
+                    let status = match result {
+                        Ok(()) => StatusCode::Ok,
+                        Err(()) => StatusCode::ServiceUnavailable,
+                    };
+
+                    Self::respond(&method, status, Headers::new(), Vec::new())
+                }),
+        )
+    }
+
+    fn check_purge(
+        remote: Option<IpAddr>,
+        purge_allow: &[IpAddr],
+        purge_entries: bool,
+    ) -> Result<(), StatusCode> {
+        // Notice: PURGE is disabled unless clients are allowed to use it, as anyone able to \
+        //   reach Bloom could otherwise empty the cache of any route. It is also disabled if \
+        //   routes are not tagged with their entry, as there would be nothing to purge.
+        if purge_allow.is_empty() || !purge_entries {
+            return Err(StatusCode::MethodNotAllowed);
+        }
+
+        if remote.is_some_and(|remote| purge_allow.contains(&remote)) {
+            Ok(())
+        } else {
+            Err(StatusCode::Forbidden)
+        }
+    }
+
     fn accept(req: Request) -> ProxyServeResponseFuture {
         Self::tunnel(req)
     }
@@ -54,103 +118,242 @@ impl ProxyServe {
 
         headers.set::<HeaderBloomStatus>(HeaderBloomStatus(HeaderBloomStatusValue::Reject));
 
-        Self::respond(req.method(), status, headers, format!("{status}"))
+        Self::respond(
+            req.method(),
+            status,
+            headers,
+            format!("{status}").into_bytes(),
+        )
     }
 
     fn tunnel(req: Request) -> ProxyServeResponseFuture {
         let (method, uri, version, headers, body) = req.deconstruct();
         let (headers, auth, shard) = ProxyHeader::parse_from_request(headers);
 
-        let auth_hash = CacheRoute::hash(&auth);
+        let rule = CacheRoute::match_rule(&method, uri.path(), &headers);
+
+        // Request body is part of the cache key? Buffer it, so that it can be hashed
+        let body_rule = rule.filter(|rule| CacheCheck::from_request_body(&method, rule, &headers));
+
+        // Notice: POST request bodies that did not get hashed into the cache key (eg. \
+        //   too large) must never be cached, hence the route rule that gets ignored.
+        let rule = rule.filter(|_| method != Method::Post);
+
+        // Request context can only be built once the request body got hashed, if needed
+        let context = move |rule, body_hash: Option<&str>| {
+            let auth_hash = CacheRoute::hash(&auth);
+
+            let (key, key_mask) = CacheRoute::gen_key_cache(
+                shard,
+                &auth_hash,
+                &CacheRouteRequest {
+                    version,
+                    method: &method,
+                    path: uri.path(),
+                    query: uri.query(),
+                    headers: &headers,
+                    body_hash,
+                },
+                CacheRoute::match_key_rule(uri.path()),
+            );
+
+            CacheContext {
+                shard,
+                auth_hash,
+                key,
+                key_mask,
+                method,
+                uri,
+                headers,
+                rule,
+            }
+        };
 
-        let (ns, ns_mask) = CacheRoute::gen_key_cache(
-            shard,
-            &auth_hash,
-            version,
-            &method,
-            uri.path(),
-            uri.query(),
-            headers.get::<Origin>(),
-        );
+        match body_rule {
+            Some(body_rule) => Box::new(body.concat2().and_then(
+                move |body_chunk| -> ProxyServeResponseFuture {
+                    let body_bytes = body_chunk.to_vec();
+                    let body_hash =
+                        CacheRoute::gen_body_hash(&body_bytes, body_rule.post_canonical_json);
+
+                    Self::tunnel_keyed(
+                        context(Some(body_rule), Some(&body_hash)),
+                        Body::from(body_bytes),
+                    )
+                },
+            )),
+            None => Self::tunnel_keyed(context(rule, None), body),
+        }
+    }
+
+    fn tunnel_keyed(context: CacheContext, body: Body) -> ProxyServeResponseFuture {
+        info!("tunneling for ns = {}", context.key);
 
-        info!("tunneling for ns = {}", ns);
+        // Route rule bypasses cache? Tunnel request straight to the API
+        if context.rule.is_some_and(|rule| rule.bypass) {
+            debug!("bypassing cache for ns = {}", context.key);
+
+            return Self::tunnel_over_proxy(context, body, None);
+        }
 
         Box::new(
-            Self::fetch_cached_data(shard, &ns, &method, &headers)
+            Self::fetch_cached_data(&context)
                 .or_else(|()| Err(Error::Incomplete))
                 .and_then(move |result| match result {
-                    Ok(value) => Self::dispatch_cached(
-                        shard, ns, ns_mask, auth_hash, method, uri, version, headers, body,
-                        value.0, value.1,
-                    ),
-                    Err(()) => Self::tunnel_over_proxy(
-                        shard, ns, ns_mask, auth_hash, method, uri, version, headers, body,
-                    ),
+                    Ok(value) => {
+                        Self::dispatch_cached(context, body, value.0, value.1, value.2, false)
+                    }
+                    Err(fallback) => Self::tunnel_coalesced(context, body, fallback),
                 }),
         )
     }
 
-    fn fetch_cached_data(
-        shard: u8,
-        ns: &str,
-        method: &Method,
-        headers: &Headers,
-    ) -> ProxyServeResultFuture {
-        // Clone inner If-None-Match header value (pass it to future)
-        let header_if_none_match = headers
-            .get::<IfNoneMatch>()
-            .map(std::borrow::ToOwned::to_owned);
-        let ns_string = ns.to_string();
+    fn tunnel_coalesced(
+        context: CacheContext,
+        body: Body,
+        fallback: Option<(String, String)>,
+    ) -> ProxyServeResponseFuture {
+        // Only requests that can be served from cache can wait on another request
+        if !APP_CONF.cache.coalesce
+            || APP_CONF.cache.disable_read
+            || !CacheCheck::from_request(&context.method, context.rule)
+        {
+            return Self::tunnel_over_proxy(context, body, fallback);
+        }
 
         Box::new(
-            CacheRead::acquire_meta(shard, ns, method)
-                .and_then(move |result| {
-                    match result {
-                        Ok(fingerprint) => {
-                            debug!(
-                                "got fingerprint for cached data = {} on ns = {}",
-                                &fingerprint, &ns_string
+            ProxyCoalesce::join(context.shard, &context.key, &context.key_mask)
+                .or_else(|()| Err(Error::Incomplete))
+                .and_then(move |role| -> ProxyServeResponseFuture {
+                    match role {
+                        ProxyCoalesceRole::Leader(mut leader) => Box::new(
+                            Self::tunnel_over_proxy(context, body, fallback).then(move |result| {
+                                // API failed? The expired response was served instead
+                                if result.as_ref().map_or(true, Self::is_fallback_response) {
+                                    leader.fail();
+                                }
+
+                                // Response is now cached, release followers
+                                drop(leader);
+
+                                result
+                            }),
+                        ),
+                        ProxyCoalesceRole::Follower { leader_failed } => {
+                            Self::tunnel_follower(context, body, fallback, leader_failed)
+                        }
+                    }
+                }),
+        )
+    }
+
+    fn tunnel_follower(
+        context: CacheContext,
+        body: Body,
+        fallback: Option<(String, String)>,
+        leader_failed: bool,
+    ) -> ProxyServeResponseFuture {
+        // The response fetched by the leader request should now be cached, though if it is \
+        //   not (eg. it was not cacheable), the request gets tunneled on its own.
+        Box::new(
+            Self::fetch_cached_data(&context)
+                .or_else(|()| Err(Error::Incomplete))
+                .and_then(move |result| match result {
+                    Ok(value) => {
+                        Self::dispatch_cached(context, body, value.0, value.1, value.2, true)
+                    }
+                    Err(expired) => match (expired.or(fallback), leader_failed) {
+                        // API failed for the leader? Serve the expired response right away
+                        (Some((ns_fallback, fingerprint)), true) => {
+                            warn!(
+                                "serving expired ns = {} as api failed for coalesced request",
+                                ns_fallback
                             );
 
-                            // Check if not modified?
-                            let isnt_modified =
-                                header_if_none_match
-                                    .as_ref()
-                                    .map_or(false, |req_if_none_match| match req_if_none_match {
-                                        &IfNoneMatch::Any => true,
-                                        IfNoneMatch::Items(req_etags) => {
-                                            if let Some(req_etag) = req_etags.first() {
-                                                req_etag.weak_eq(&EntityTag::new(
-                                                    false,
-                                                    fingerprint.clone(),
-                                                ))
-                                            } else {
-                                                false
-                                            }
-                                        }
-                                    });
+                            Self::dispatch_fallback(ns_fallback, fingerprint, context.method)
+                        }
+                        (fallback, _) => Self::tunnel_over_proxy(context, body, fallback),
+                    },
+                }),
+        )
+    }
 
-                            debug!(
-                                "got not modified status for cached data = {} on ns = {}",
-                                &isnt_modified, &ns_string
-                            );
+    fn fetch_cached_data(context: &CacheContext) -> ProxyServeResultFuture {
+        // Clone inner If-None-Match header value (pass it to future)
+        let header_if_none_match = context
+            .headers
+            .get::<IfNoneMatch>()
+            .map(std::borrow::ToOwned::to_owned);
 
-                            Self::fetch_cached_data_body(&ns_string, fingerprint, !isnt_modified)
+        Box::new(
+            CacheRead::acquire_meta(
+                context.shard,
+                &context.key,
+                &context.method,
+                &context.headers,
+                context.rule,
+            )
+            .and_then(move |result| -> ProxyServeResultFuture {
+                match result {
+                    // Notice: the namespace may differ from the requested one, if it varies
+                    Ok((ns_string, meta)) => {
+                        let (fingerprint, freshness) = (meta.fingerprint.clone(), meta.freshness());
+
+                        debug!(
+                            "got fingerprint for cached data = {} on ns = {} ({:?})",
+                            &fingerprint, &ns_string, freshness
+                        );
+
+                        // Expired? Only keep it as a fallback, if the API fails
+                        if freshness == CacheStoreFreshness::Expired {
+                            return Box::new(future::ok(Err(Some((ns_string, fingerprint)))));
                         }
-                        _ => Box::new(future::ok(Err(()))),
+
+                        // Check if not modified?
+                        let isnt_modified =
+                            header_if_none_match
+                                .as_ref()
+                                .is_some_and(|req_if_none_match| match req_if_none_match {
+                                    &IfNoneMatch::Any => true,
+                                    IfNoneMatch::Items(req_etags) => {
+                                        if let Some(req_etag) = req_etags.first() {
+                                            req_etag.weak_eq(&EntityTag::new(
+                                                false,
+                                                fingerprint.clone(),
+                                            ))
+                                        } else {
+                                            false
+                                        }
+                                    }
+                                });
+
+                        debug!(
+                            "got not modified status for cached data = {} on ns = {}",
+                            &isnt_modified, &ns_string
+                        );
+
+                        Self::fetch_cached_data_body(
+                            &ns_string,
+                            fingerprint,
+                            freshness == CacheStoreFreshness::Stale,
+                            !isnt_modified,
+                        )
                     }
-                })
-                .or_else(|()| {
-                    error!("failed fetching cached data meta");
+                    _ => Box::new(future::ok(Err(None))),
+                }
+            })
+            .or_else(|()| {
+                error!("failed fetching cached data meta");
 
-                    future::ok(Err(()))
-                }),
+                future::ok(Err(None))
+            }),
         )
     }
 
     fn fetch_cached_data_body(
         ns: &str,
         fingerprint: String,
+        is_stale: bool,
         do_acquire_body: bool,
     ) -> ProxyServeResultFuture {
         // Do not acquire body? (not modified)
@@ -163,156 +366,304 @@ impl ProxyServe {
 
         Box::new(
             body_fetcher
-                .and_then(|body_result| {
+                .and_then(move |body_result| {
                     body_result
                         .map_err(|_| ())
-                        .map(|body| Ok((fingerprint, body)))
+                        .map(|body| Ok((fingerprint, body, is_stale)))
                 })
                 .or_else(|e| {
                     error!("failed fetching cached data body {:?}", e);
 
-                    future::ok(Err(()))
+                    future::ok(Err(None))
                 }),
         )
     }
 
     fn tunnel_over_proxy(
-        shard: u8,
-        ns: String,
-        ns_mask: String,
-        auth_hash: String,
-        method: Method,
-        uri: Uri,
-        version: HttpVersion,
-        headers: Headers,
+        context: CacheContext,
         body: Body,
+        fallback: Option<(String, String)>,
     ) -> ProxyServeResponseFuture {
         // Clone method value for closures. Sadly, it looks like Rust borrow \
         //   checker doesnt discriminate properly on this check.
-        let method_success = method.clone();
-        let method_failure = method.clone();
+        let method_success = context.method.clone();
+        let method_failure = context.method.clone();
+
+        let has_fallback = fallback.is_some();
 
         Box::new(
-            ProxyTunnel::run(&method, &uri, &headers, body, shard)
-                .and_then(move |tunnel_res| {
-                    CacheWrite::save(
-                        ns,
-                        ns_mask,
-                        auth_hash,
-                        shard,
-                        method,
-                        version,
-                        tunnel_res.status(),
-                        tunnel_res.headers().to_owned(),
-                        tunnel_res.body(),
-                    )
-                })
-                .and_then(move |mut result| match result.body {
-                    Ok(body_string) => Self::dispatch_fetched(
-                        &method_success,
-                        &result.status,
-                        result.headers,
-                        HeaderBloomStatusValue::Miss,
-                        body_string,
-                        result.fingerprint,
-                    ),
-                    Err(body_string_values) => {
-                        match body_string_values {
-                            Some(body_string) => {
-                                // Enforce clean headers, as usually they get \
-                                //   cleaned from cache writer
-                                HeaderJanitor::clean(&mut result.headers);
-
-                                Self::dispatch_fetched(
-                                    &method_success,
-                                    &result.status,
-                                    result.headers,
-                                    HeaderBloomStatusValue::Direct,
-                                    body_string,
-                                    result.fingerprint,
-                                )
-                            }
-                            _ => Self::dispatch_failure(&method_success),
+            ProxyTunnel::run(
+                &context.method,
+                &context.uri,
+                &context.headers,
+                body,
+                context.shard,
+            )
+            .and_then(move |tunnel_res| -> CacheWriteResultFuture {
+                // API errored? Fail there, so that the expired response gets served instead
+                if has_fallback && Self::is_upstream_error(tunnel_res.status()) {
+                    return Box::new(future::err(Error::Status));
+                }
+
+                Self::save_fetched(context, tunnel_res)
+            })
+            .and_then(move |mut result| match result.body {
+                Ok(body_bytes) => Self::dispatch_fetched(
+                    &method_success,
+                    &result.status,
+                    result.headers,
+                    HeaderBloomStatusValue::Miss,
+                    body_bytes,
+                    result.fingerprint,
+                ),
+                Err(body_bytes_values) => {
+                    match body_bytes_values {
+                        Some(body_bytes) => {
+                            // Enforce clean headers, as usually they get \
+                            //   cleaned from cache writer
+                            HeaderJanitor::clean(&mut result.headers);
+
+                            Self::dispatch_fetched(
+                                &method_success,
+                                &result.status,
+                                result.headers,
+                                HeaderBloomStatusValue::Direct,
+                                body_bytes,
+                                result.fingerprint,
+                            )
                         }
+                        _ => Self::dispatch_failure(&method_success),
                     }
-                })
-                .or_else(move |_| Self::dispatch_failure(&method_failure)),
+                }
+            })
+            .or_else(move |err| match fallback {
+                Some((ns_fallback, fingerprint)) => {
+                    warn!(
+                        "serving expired ns = {} as api failed because: {}",
+                        ns_fallback, err
+                    );
+
+                    Self::dispatch_fallback(ns_fallback, fingerprint, method_failure)
+                }
+                None => Self::dispatch_failure(&method_failure),
+            }),
         )
     }
 
+    fn save_fetched(context: CacheContext, tunnel_res: Response) -> CacheWriteResultFuture {
+        // Invalidate cache before responding, so that the client reads its own writes
+        // Notice: this applies to all fetched responses, including background revalidations \
+        //   and responses fetched on behalf of coalesced requests.
+        let mut invalidations = Vec::new();
+
+        // Mutating request succeeded? Invalidate the cached routes it affects
+        if tunnel_res.status().is_success()
+            && !CacheCheck::from_request(&context.method, context.rule)
+        {
+            invalidations.push(CacheInvalidate::from_request(
+                context.shard,
+                &context.auth_hash,
+                &context.method,
+                context.uri.path(),
+            ));
+        }
+
+        // API requested buckets to be purged? (eg. as it just updated their data)
+        if let Some(purge_buckets) = tunnel_res
+            .headers()
+            .get::<HeaderResponseBloomResponsePurgeBuckets>()
+        {
+            invalidations.push(CacheInvalidate::from_buckets(
+                context.shard,
+                &purge_buckets.0,
+            ));
+        }
+
+        let save = CacheWrite::save(
+            context,
+            tunnel_res.status(),
+            tunnel_res.headers().to_owned(),
+            tunnel_res.body(),
+        );
+
+        if invalidations.is_empty() {
+            save
+        } else {
+            // Notice: failed invalidations must not cancel pending ones
+            let invalidations = invalidations
+                .into_iter()
+                .map(|invalidation| invalidation.then(|_| Ok::<(), ()>(())));
+
+            Box::new(future::join_all(invalidations).then(move |_| save))
+        }
+    }
+
     fn dispatch_cached(
-        shard: u8,
-        ns: String,
-        ns_mask: String,
-        auth_hash: String,
-        method: Method,
-        req_uri: Uri,
-        req_version: HttpVersion,
-        req_headers: Headers,
+        context: CacheContext,
         req_body: Body,
         res_fingerprint: String,
-        res_string: Option<String>,
+        res_bytes: Option<Vec<u8>>,
+        res_stale: bool,
+        res_coalesced: bool,
     ) -> ProxyServeResponseFuture {
-        // Response modified? (non-empty body)
-        if let Some(res_string_value) = res_string {
-            let mut headers = [httparse::EMPTY_HEADER; CACHED_PARSE_MAX_HEADERS];
-            let mut res = httparse::Response::new(&mut headers);
-
-            // Split headers from body
-            let body = Self::parse_response_body(&res_string_value);
-
-            match res.parse(res_string_value.as_bytes()) {
-                Ok(_) => {
-                    // Process cached status
-                    let code = res.code.unwrap_or(500u16);
-                    let status =
-                        StatusCode::try_from(code).unwrap_or(StatusCode::Unregistered(code));
-
-                    // Process cached headers
-                    let mut headers = Headers::new();
-
-                    for header in res.headers {
-                        if let (Ok(header_name), Ok(header_value)) = (
-                            String::from_utf8(Vec::from(header.name)),
-                            String::from_utf8(Vec::from(header.value)),
-                        ) {
-                            headers.set_raw(header_name, header_value);
-                        }
-                    }
+        // Decode response, if modified (non-empty body)
+        let envelope = match res_bytes
+            .map(|res_bytes_value| CacheEnvelope::decode(&res_bytes_value))
+            .transpose()
+        {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                error!("failed decoding cached response: {:?}", err);
+
+                return Self::tunnel_over_proxy(context, req_body, None);
+            }
+        };
 
-                    ProxyHeader::set_etag(&mut headers, Self::fingerprint_etag(res_fingerprint));
+        let method = context.method.clone();
 
-                    headers
-                        .set::<HeaderBloomStatus>(HeaderBloomStatus(HeaderBloomStatusValue::Hit));
+        // Serve stale response right away, and refresh it in the background
+        let bloom_status = if res_stale {
+            Self::revalidate(context, req_body);
 
-                    // Serve cached response
-                    Self::respond(&method, status, headers, body)
-                }
-                Err(err) => {
-                    error!("failed parsing cached response: {}", err);
-
-                    Self::tunnel_over_proxy(
-                        shard,
-                        ns,
-                        ns_mask,
-                        auth_hash,
-                        method,
-                        req_uri,
-                        req_version,
-                        req_headers,
-                        req_body,
-                    )
-                }
-            }
+            HeaderBloomStatusValue::Stale
+        } else if res_coalesced {
+            HeaderBloomStatusValue::Coalesced
+        } else {
+            HeaderBloomStatusValue::Hit
+        };
+
+        if let Some(envelope) = envelope {
+            Self::dispatch_envelope(&method, envelope, res_fingerprint, bloom_status)
         } else {
             // Response not modified for client, process non-modified + cached headers
             let mut headers = Headers::new();
 
             ProxyHeader::set_etag(&mut headers, Self::fingerprint_etag(res_fingerprint));
-            headers.set::<HeaderBloomStatus>(HeaderBloomStatus(HeaderBloomStatusValue::Hit));
+            headers.set::<HeaderBloomStatus>(HeaderBloomStatus(bloom_status));
 
             // Serve non-modified response
-            Self::respond(&method, StatusCode::NotModified, headers, String::new())
+            Self::respond(&method, StatusCode::NotModified, headers, Vec::new())
+        }
+    }
+
+    fn dispatch_fallback(
+        ns: String,
+        fingerprint: String,
+        method: Method,
+    ) -> ProxyServeResponseFuture {
+        Box::new(
+            CacheRead::acquire_body(&ns).then(move |result| match result {
+                Ok(Ok(Some(res_bytes))) => match CacheEnvelope::decode(&res_bytes) {
+                    Ok(envelope) => Self::dispatch_envelope(
+                        &method,
+                        envelope,
+                        fingerprint,
+                        HeaderBloomStatusValue::Stale,
+                    ),
+                    Err(err) => {
+                        error!("failed decoding expired response: {:?}", err);
+
+                        Self::dispatch_failure(&method)
+                    }
+                },
+                _ => {
+                    error!("failed fetching expired response on ns = {}", ns);
+
+                    Self::dispatch_failure(&method)
+                }
+            }),
+        )
+    }
+
+    fn dispatch_envelope(
+        method: &Method,
+        envelope: CacheEnvelope,
+        fingerprint: String,
+        bloom_status: HeaderBloomStatusValue,
+    ) -> ProxyServeResponseFuture {
+        // Process cached status
+        let status = StatusCode::try_from(envelope.status)
+            .unwrap_or(StatusCode::Unregistered(envelope.status));
+
+        // Process cached headers
+        let mut headers = Headers::new();
+
+        for (header_name, header_value) in envelope.headers {
+            headers.append_raw(header_name, header_value);
+        }
+
+        ProxyHeader::set_etag(&mut headers, Self::fingerprint_etag(fingerprint));
+
+        headers.set::<HeaderBloomStatus>(HeaderBloomStatus(bloom_status));
+
+        // Serve cached response
+        Self::respond(method, status, headers, envelope.body)
+    }
+
+    fn revalidate(mut context: CacheContext, body: Body) {
+        let ns = context.key.to_owned();
+
+        // Only one refresh per cache key at any time, as a stale key can get many hits
+        match REVALIDATE_REGISTER.lock() {
+            Ok(mut register) => {
+                if !register.insert(ns.to_owned()) {
+                    debug!("already revalidating ns = {}", ns);
+
+                    return;
+                }
+            }
+            Err(_) => return,
+        }
+
+        let remote = match LISTEN_REMOTE.lock() {
+            Ok(mut remote) => remote.get_mut().to_owned(),
+            Err(_) => None,
+        };
+
+        if let Some(remote) = remote {
+            info!("revalidating stale ns = {}", ns);
+
+            // Conditional request headers must not reach the API, as a full response is needed
+            context.headers.remove::<IfNoneMatch>();
+            context.headers.remove::<IfModifiedSince>();
+
+            remote.spawn(move |_| {
+                let ns_revalidated = ns.to_owned();
+
+                ProxyTunnel::run(
+                    &context.method,
+                    &context.uri,
+                    &context.headers,
+                    body,
+                    context.shard,
+                )
+                .and_then(move |tunnel_res| Self::save_fetched(context, tunnel_res))
+                .then(move |result| {
+                    match result {
+                        Ok(result) => debug!(
+                            "revalidated ns = {} (written = {})",
+                            ns_revalidated,
+                            result.body.is_ok()
+                        ),
+                        Err(err) => warn!(
+                            "failed revalidating ns = {} because: {}",
+                            ns_revalidated, err
+                        ),
+                    }
+
+                    Self::unregister_revalidate(&ns_revalidated);
+
+                    Ok(())
+                })
+            });
+        } else {
+            Self::unregister_revalidate(&ns);
+        }
+    }
+
+    fn unregister_revalidate(ns: &str) {
+        if let Ok(mut register) = REVALIDATE_REGISTER.lock() {
+            register.remove(ns);
         }
     }
 
@@ -321,7 +672,7 @@ impl ProxyServe {
         status: &StatusCode,
         mut headers: Headers,
         bloom_status: HeaderBloomStatusValue,
-        body_string: String,
+        body_bytes: Vec<u8>,
         fingerprint: Option<String>,
     ) -> ProxyServeResponseFuture {
         // Process ETag for content?
@@ -331,7 +682,7 @@ impl ProxyServe {
 
         headers.set(HeaderBloomStatus(bloom_status));
 
-        Self::respond(method, *status, headers, body_string)
+        Self::respond(method, *status, headers, body_bytes)
     }
 
     fn dispatch_failure(method: &Method) -> ProxyServeResponseFuture {
@@ -341,48 +692,44 @@ impl ProxyServe {
 
         headers.set::<HeaderBloomStatus>(HeaderBloomStatus(HeaderBloomStatusValue::Offline));
 
-        Self::respond(method, status, headers, format!("{status}"))
+        Self::respond(method, status, headers, format!("{status}").into_bytes())
     }
 
-    fn fingerprint_etag(fingerprint: String) -> ETag {
-        ETag(EntityTag::new(false, fingerprint))
+    fn is_fallback_response(response: &Response) -> bool {
+        // Only expired responses served as a fallback to an API failure are stale at this point
+        response
+            .headers()
+            .get::<HeaderBloomStatus>()
+            .is_some_and(|status| matches!(status.0, HeaderBloomStatusValue::Stale))
     }
 
-    fn parse_response_body(res_string_value: &str) -> String {
-        let (mut body, mut is_last_line_empty) = (String::new(), false);
-
-        // Scan response lines
-        let lines = res_string_value.lines().with_position();
-
-        for (position, line) in lines {
-            if !body.is_empty() || is_last_line_empty {
-                // Append line to body
-                body.push_str(line);
-
-                // Append line feed character?
-                if matches!(position, Position::First | Position::Middle) {
-                    body.push_str(LINE_FEED);
-                }
-            }
-
-            is_last_line_empty = line.is_empty();
-        }
+    fn is_upstream_error(status: StatusCode) -> bool {
+        // Those are the errors that allow for a stale response to be served (RFC 5861)
+        matches!(
+            status,
+            StatusCode::InternalServerError
+                | StatusCode::BadGateway
+                | StatusCode::ServiceUnavailable
+                | StatusCode::GatewayTimeout
+        )
+    }
 
-        body
+    fn fingerprint_etag(fingerprint: String) -> ETag {
+        ETag(EntityTag::new(false, fingerprint))
     }
 
     fn respond(
         method: &Method,
         status: StatusCode,
         headers: Headers,
-        body_string: String,
+        body_bytes: Vec<u8>,
     ) -> ProxyServeResponseFuture {
         Box::new(future::ok(match method {
             &Method::Get | &Method::Post | &Method::Patch | &Method::Put | &Method::Delete => {
                 Response::new()
                     .with_status(status)
                     .with_headers(headers)
-                    .with_body(body_string)
+                    .with_body(body_bytes)
             }
             _ => Response::new().with_status(status).with_headers(headers),
         }))
@@ -394,13 +741,28 @@ mod tests {
     use super::*;
 
     #[test]
-    fn it_parses_response_body() {
-        let body = "2022-10-03";
-        let headers =
-            "Content-Type: text/plain; charset=utf-8\nServer: Kestrel\nTransfer-Encoding: chunked";
-
-        let response_string = format!("{headers}\n\n{body}");
+    fn it_checks_purge_requests() {
+        let (allowed, denied) = ("127.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
 
-        assert_eq!(body, ProxyServe::parse_response_body(&response_string));
+        assert_eq!(
+            ProxyServe::check_purge(Some(allowed), &[allowed], true),
+            Ok(())
+        );
+        assert_eq!(
+            ProxyServe::check_purge(Some(denied), &[allowed], true),
+            Err(StatusCode::Forbidden)
+        );
+        assert_eq!(
+            ProxyServe::check_purge(None, &[allowed], true),
+            Err(StatusCode::Forbidden)
+        );
+        assert_eq!(
+            ProxyServe::check_purge(Some(allowed), &[], true),
+            Err(StatusCode::MethodNotAllowed)
+        );
+        assert_eq!(
+            ProxyServe::check_purge(Some(allowed), &[allowed], false),
+            Err(StatusCode::MethodNotAllowed)
+        );
     }
 }
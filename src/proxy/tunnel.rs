@@ -4,12 +4,14 @@
 // Copyright: 2017, Valerian Saliou <valerian@valeriansaliou.name>
 // License: Mozilla Public License v2.0 (MPL v2.0)
 
-use futures::{future, Future};
+use futures::future::{self, Either};
+use futures::Future;
 use hyper::client::{HttpConnector, Response};
 use hyper::{Body, Client, Error, Headers, Method, Request, Uri};
 use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
 
-use crate::server::listen::LISTEN_REMOTE;
+use crate::server::listen::LISTEN_HANDLE;
 use crate::APP_CONF;
 
 const MAX_SHARDS: u8 = 16;
@@ -31,16 +33,7 @@ fn make_client() -> Client<HttpConnector> {
     Client::configure()
         .keep_alive(true)
         .keep_alive_timeout(Some(Duration::from_secs(CLIENT_KEEP_ALIVE_TIMEOUT_SECONDS)))
-        .build(
-            &LISTEN_REMOTE
-                .lock()
-                .unwrap()
-                .get_mut()
-                .to_owned()
-                .unwrap()
-                .handle()
-                .unwrap(),
-        )
+        .build(&LISTEN_HANDLE.with(Handle::clone))
 }
 
 fn map_shards() -> [Option<Uri>; MAX_SHARDS as usize] {
@@ -114,7 +107,7 @@ impl ProxyTunnel {
                                 _ => {}
                             }
 
-                            TUNNEL_CLIENT.with(|client| Box::new(client.request(tunnel_req)))
+                            Self::expire(TUNNEL_CLIENT.with(|client| client.request(tunnel_req)))
                         }
                         Err(err) => Box::new(future::err(Error::Uri(err))),
                     }
@@ -126,4 +119,27 @@ impl ProxyTunnel {
             Box::new(future::err(Error::Header))
         }
     }
+
+    fn expire<F>(response: F) -> ProxyTunnelFuture
+    where
+        F: Future<Item = Response, Error = Error> + 'static,
+    {
+        if APP_CONF.proxy.tunnel_timeout == 0 {
+            return Box::new(response);
+        }
+
+        // Race response against timeout, a slow API is then handled as a failed API
+        match Timeout::new(
+            Duration::from_secs(APP_CONF.proxy.tunnel_timeout),
+            &LISTEN_HANDLE.with(Handle::clone),
+        ) {
+            Ok(timeout) => Box::new(response.select2(timeout).then(|result| match result {
+                Ok(Either::A((response, _))) => Ok(response),
+                Ok(Either::B(_)) => Err(Error::Timeout),
+                Err(Either::A((err, _))) => Err(err),
+                Err(Either::B((err, _))) => Err(Error::Io(err)),
+            })),
+            Err(err) => Box::new(future::err(Error::Io(err))),
+        }
+    }
 }
@@ -7,7 +7,7 @@
 use hyper::server::Http;
 use std::cell::Cell;
 use std::sync::{Arc, Mutex};
-use tokio_core::reactor::Remote;
+use tokio_core::reactor::{Handle, Remote};
 
 use super::handle::ServerRequestHandle;
 use crate::APP_CONF;
@@ -17,10 +17,27 @@ lazy_static! {
         Arc::new(Mutex::new(Cell::new(None)));
 }
 
+// Notice: the handle can only be acquired from the thread running the server event loop
+thread_local! {
+    pub static LISTEN_HANDLE: Handle = acquire_handle();
+}
+
 pub struct ServerListenBuilder;
 pub struct ServerListen;
 
+fn acquire_handle() -> Handle {
+    LISTEN_REMOTE
+        .lock()
+        .unwrap()
+        .get_mut()
+        .to_owned()
+        .unwrap()
+        .handle()
+        .unwrap()
+}
+
 impl ServerListenBuilder {
+    #[allow(clippy::new_ret_no_self)]
     pub const fn new() -> ServerListen {
         ServerListen {}
     }